@@ -0,0 +1,106 @@
+use crate::parse::ProgramConfig;
+use crate::reexec;
+use std::collections::BTreeMap;
+use std::io;
+use std::net::{TcpListener, ToSocketAddrs};
+use std::os::fd::{AsRawFd, OwnedFd, RawFd};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::UnixListener;
+use std::path::Path;
+use std::sync::Mutex;
+use tracing::info;
+
+
+// Prefix of the program sockets' names in the self-upgrade handoff, followed by the address
+const HANDOFF_PREFIX: &str = "socket:";
+
+// Listening sockets bound for the programs' 'sockets', by address, kept open for the supervisor's lifetime
+static BOUND: Mutex<BTreeMap<String, OwnedFd>> = Mutex::new(BTreeMap::new());
+
+
+
+
+
+
+/*
+    @@@
+    @parse_socket();
+    . Splits a 'sockets' entry into its name and address: "0.0.0.0:8080", "/run/app.sock" or "http=0.0.0.0:8080".
+    . The name defaults to the program's, it is what the program finds in LISTEN_FDNAMES.
+    . An address with a '/' is a Unix socket path, anything else must be a host:port.
+*/
+pub fn parse_socket<'a>(entry: &'a str, program: &'a str) -> Result<(&'a str, &'a str), String> {
+    let (name, address) = match entry.split_once('=') {
+        Some((name, address)) => (name, address),
+        None => (program, entry),
+    };
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || "_-.".contains(c)) {
+        return Err(format!("`{}` is not a valid socket name (letters, digits, '_', '-' and '.')", name));
+    }
+    if !address.contains('/') && address.to_socket_addrs().map(|mut addrs| addrs.next().is_none()).unwrap_or(true) {
+        return Err(format!("`{}` is not a host:port address or a Unix socket path", address));
+    }
+    Ok((name, address))
+}
+
+fn bind(address: &str) -> io::Result<OwnedFd> {
+    let fd = if address.contains('/') {
+        if let Some(dir) = Path::new(address).parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        // A socket file left by a previous run would make bind() fail, anything else at that path is left alone
+        if std::fs::symlink_metadata(address).is_ok_and(|meta| meta.file_type().is_socket()) {
+            std::fs::remove_file(address)?;
+        }
+        OwnedFd::from(UnixListener::bind(address)?)
+    } else {
+        OwnedFd::from(TcpListener::bind(address)?)
+    };
+    info!(address, "Listening for programs");
+    Ok(fd)
+}
+
+
+
+
+
+
+/*
+    @@@
+    @resume();
+    . Takes back the program sockets handed over by a self-upgrade, at startup, whether or not a program still lists them.
+    . They are registered again right away, so a further upgrade hands them over too and bind() never races the old socket.
+*/
+pub fn resume() {
+    let mut bound = BOUND.lock().unwrap_or_else(|e| e.into_inner());
+    for (name, fd) in reexec::inherited_listeners(HANDOFF_PREFIX) {
+        reexec::register_listener(&name, fd.as_raw_fd());
+        bound.insert(name[HANDOFF_PREFIX.len()..].to_string(), fd);
+    }
+}
+
+
+
+
+
+
+/*
+    @@@
+    @program_sockets();
+    . Returns the listening sockets of a program in the order of its 'sockets', with their names, binding the ones not bound yet.
+    . A socket is bound once and shared by every instance and every program listing the same address, so restarts never drop pending connections.
+    . Bound sockets are registered for self-upgrade, the new process takes them back instead of binding again.
+*/
+pub fn program_sockets(name: &str, cfg: &ProgramConfig) -> io::Result<Vec<(String, RawFd)>> {
+    let mut bound = BOUND.lock().unwrap_or_else(|e| e.into_inner());
+
+    cfg.sockets.iter().map(|entry| {
+        let (socket_name, address) = parse_socket(entry, name).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        if !bound.contains_key(address) {
+            let fd = bind(address).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", address, e)))?;
+            reexec::register_listener(&format!("{}{}", HANDOFF_PREFIX, address), fd.as_raw_fd());
+            bound.insert(address.to_string(), fd);
+        }
+        Ok((socket_name.to_string(), bound[address].as_raw_fd()))
+    }).collect()
+}
@@ -0,0 +1,61 @@
+use crate::parse::Format;
+use clap::{Parser, Subcommand};
+
+
+// Command-line arguments of the supervisor binary
+#[derive(Debug, Parser)]
+#[command(name = "supervisor", version, about = "A lightweight, asynchronous process supervisor")]
+pub struct Cli {
+    /// Path of the configuration file, remembered for `reload`
+    #[arg(short, long, default_value = "config/config.yml")]
+    pub config: String,
+
+    /// Format of the configuration file (yaml, ini, toml, json), guessed from its extension by default
+    #[arg(long)]
+    pub format: Option<Format>,
+
+    /// Validate the configuration and exit
+    #[arg(long)]
+    pub check: bool,
+
+    /// Stay attached to the terminal (default)
+    #[arg(long, conflicts_with = "daemon")]
+    pub foreground: bool,
+
+    /// Detach from the terminal and run in the background, use `ctl` to control it
+    #[arg(long)]
+    pub daemon: bool,
+
+    /// Maximum level of the supervisor log (trace, debug, info, warn, error)
+    #[arg(long, default_value = "info")]
+    pub log_level: tracing::Level,
+
+    /// Don't start the interactive shell, rely on the control socket instead
+    #[arg(long)]
+    pub no_shell: bool,
+
+    /// Container init mode: reap orphans, forward SIGTERM/SIGINT to programs, log to stdout and exit when a critical program dies
+    #[arg(long, conflicts_with = "daemon")]
+    pub init: bool,
+
+    /// Take back the instances of a previous run recorded in the state file, instead of spawning duplicates
+    #[arg(long)]
+    pub adopt: bool,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Send one command (status, start, stop, restart, reload, stats, self-upgrade) to a running supervisor
+    Ctl {
+        #[arg(required = true, trailing_var_arg = true, allow_hyphen_values = true)]
+        args: Vec<String>,
+    },
+    /// Print the YAML equivalent of a supervisord INI configuration file
+    Convert {
+        /// supervisord configuration file ([program:x] sections)
+        file: String,
+    },
+}
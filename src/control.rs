@@ -1,12 +1,34 @@
-use crate::runtime::{RuntimeJob, SupervisorState, spawn_processes};
-use crate::parse::ProgramConfig;
+use crate::runtime::{apply_config, pre_start, RuntimeJob, SupervisorState, spawn_instances, spawn_processes, watch_grace_period};
+use crate::parse::{parser, parse_signal, Config, ProgramConfig, RestartStrategy};
+use crate::stats;
+use crate::reexec;
+use crate::procfs;
+use crate::events::{self, ProcessState};
+use crate::hooks::{self, HookKind};
+use crate::expand::instance_config;
 use std::collections::HashMap;
 use nix::sys::signal::{Signal};
 use std::time::Duration;
 use nix::sys::wait::{WaitPidFlag, WaitStatus};
 use nix::unistd::Pid;
 use nix::sys::signal::killpg;
+use nix::errno::Errno;
 use nix::sys::wait::waitpid;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+
+
+// Everything a front-end (shell, control socket) needs to drive the supervisor
+#[derive(Clone)]
+pub struct Context {
+    pub config_path: String,
+    pub config: Arc<RwLock<Config>>,
+    pub state: SupervisorState,
+}
+
+
+
+
 
 
 /*
@@ -14,32 +36,42 @@ use nix::sys::wait::waitpid;
     @start_program();
     . Forks & execs program's numprocs child processes and collect their PIDs by name.
     . Acquires a write-lock on the shared supervisor state and Appends the new child handles to that job’s children list.
-    . Prints a confirmation of how many instances were started --or an error if the name wasn’t found.
+    . Returns a confirmation of how many instances were started --or an error if the name wasn’t found.
 */
 pub async fn start_program(
     name: &str,
     configs: &HashMap<String, ProgramConfig>,
     state: SupervisorState,
-) {
+) -> String {
     if let Some(cfg) = configs.get(name) {
+        if !pre_start(name, cfg, 0).await {
+            return format!("pre_start hook of `{}` failed; start aborted", name);
+        }
         let pids = spawn_processes(name, cfg);
+        watch_grace_period(name, &pids, cfg.starttime);
         {
             let mut map = state.write().await;
             let job = map
                 .entry(name.to_string())
                 .or_insert_with(|| RuntimeJob {
                     config: cfg.clone(),
-                    children: Vec::new(),
+                    children: Default::default(),
                     retries_left: cfg.startretries,
+                    restarts: 0,
+                    last_exit: None,
+                    stopped: false,
+                    fatal: None,
                 });
 
             job.children = pids;
             job.retries_left = cfg.startretries;
+            job.stopped = false;
+            job.fatal = None;
         }
 
-        println!("Started {} instance(s) of `{}`", cfg.numprocs, name);
+        format!("Started {} instance(s) of `{}`", cfg.numprocs, name)
     } else {
-        eprintln!("No such program in config: `{}`", name);
+        format!("No such program in config: `{}`", name)
     }
 }
 
@@ -50,47 +82,67 @@ pub async fn start_program(
 /*
     @@@
     @stop_and_cleanup_pid();
-    . Sends a configurable stop signal (e.g., SIGTERM, SIGINT) to the process group of pid.
+    . Runs the pre_stop hook, then sends a configurable stop signal (e.g., SIGTERM, SIGINT) to the process group of pid.
     . Waits up to stoptime seconds, if the process exits in that window, it returns immediately.
     . Force-kills the entire group with SIGKILL if the timeout expires and the process is still alive.
+    . An adopted instance (not our child) is polled through /proc instead of waited for, its exit code is unknown.
+    . Runs the post_stop hook with the exit code, when it is known.
+    . The hooks get the config of instance 'num', placeholders replaced, like the instance itself.
 */
-pub fn stop_and_cleanup_pid(pid: Pid, cfg: &ProgramConfig) {
-    let sig = match cfg.stopsignal.to_uppercase().as_str() {
-        "TERM" | "SIGTERM" => Signal::SIGTERM,
-        "INT"  | "SIGINT"  => Signal::SIGINT,
-        "QUIT" | "SIGQUIT" => Signal::SIGQUIT,
-        "USR1" | "SIGUSR1" => Signal::SIGUSR1,
-        _                  => Signal::SIGTERM,
-    };
+pub fn stop_and_cleanup_pid(name: &str, num: usize, pid: Pid, cfg: &ProgramConfig) {
+    let sig = parse_signal(&cfg.stopsignal).unwrap_or(Signal::SIGTERM);
+    let hook_cfg = instance_config(name, cfg, num);
+
+    hooks::run_hook(HookKind::PreStop, name, &hook_cfg, Some(pid), None);
 
     let pgid = Pid::from_raw(pid.as_raw());
     tracing::info!("Sending {:?} to process group {}", sig, pgid);
+    events::process_state(name, pid, ProcessState::Running, ProcessState::Stopping, "");
     let _ = killpg(pgid, sig);
 
     let timeout = Duration::from_secs(cfg.stoptime as u64);
     let mut elapsed = Duration::ZERO;
     let interval = Duration::from_millis(100);
 
-    while elapsed < timeout {
+    let exit_code = loop {
+        if elapsed >= timeout {
+            tracing::warn!("Timeout expired. Sending SIGKILL to process group {}", pgid);
+            let _ = killpg(pgid, Signal::SIGKILL);
+            break Some(128 + Signal::SIGKILL as i32);
+        }
         match waitpid(pid, Some(WaitPidFlag::WNOHANG)) {
             Ok(WaitStatus::StillAlive) => {
                 std::thread::sleep(interval);
                 elapsed += interval;
-                continue;
             }
             Ok(status) => {
                 tracing::info!("Process {} exited with status {:?}", pid, status);
-                return;
+                break match status {
+                    WaitStatus::Exited(_, code) => Some(code),
+                    WaitStatus::Signaled(_, sig, _) => Some(128 + sig as i32),
+                    _ => None,
+                };
             }
+            // An adopted instance isn't our child, poll it until it's gone (or a zombie its new parent didn't reap)
+            Err(Errno::ECHILD) => match procfs::read_stat(pid) {
+                Some(stat) if stat.state != 'Z' => {
+                    std::thread::sleep(interval);
+                    elapsed += interval;
+                }
+                _ => {
+                    tracing::info!("Process {} exited", pid);
+                    break None;
+                }
+            },
             Err(e) => {
                 tracing::warn!("waitpid error for {}: {:?}", pid, e);
-                return;
+                break None;
             }
         }
-    }
+    };
 
-    tracing::warn!("Timeout expired. Sending SIGKILL to process group {}", pgid);
-    let _ = killpg(pgid, Signal::SIGKILL);
+    events::process_state(name, pid, ProcessState::Stopping, ProcessState::Stopped, "");
+    hooks::run_hook(HookKind::PostStop, name, &hook_cfg, Some(pid), exit_code);
 }
 
 
@@ -102,20 +154,303 @@ pub fn stop_and_cleanup_pid(pid: Pid, cfg: &ProgramConfig) {
     @@@
     @stop_program();
     . Stops a running program by name with looking up the program in the shared state.
-    . Sends stop signals to all its child processes and clears the list of child PIDs.
-    . Puts the program back into the state map to keep track of the its config.
+    . Marks it stopped and detaches its instances under the state lock, so neither the reaper nor a pending restart brings them back.
+    . Stops them concurrently off the async workers (stop hooks, stopsignal, stoptime), without holding the state lock.
 */
-pub async fn stop_program(name: &str, state: SupervisorState) {
+pub async fn stop_program(name: &str, state: SupervisorState) -> String {
+    let (old, cfg) = {
+        let mut map = state.write().await;
+        let Some(job) = map.get_mut(name) else {
+            return format!("No such program: {}", name);
+        };
+        job.stopped = true;
+        (std::mem::take(&mut job.children), job.config.clone())
+    };
+
+    let stops: Vec<_> = old.into_iter().map(|(num, pid)| {
+        let (prog, prog_cfg) = (name.to_string(), cfg.clone());
+        tokio::task::spawn_blocking(move || stop_and_cleanup_pid(&prog, num, pid, &prog_cfg))
+    }).collect();
+    for stop in stops {
+        let _ = stop.await;
+    }
+    format!("Stopped `{}`", name)
+}
+
+
+
+
+
+
+/*
+    @@@
+    @restart_program();
+    . Restarts a program according to its 'restart_strategy', or instance by instance when 'rolling' (restart --rolling).
+    . "all" stops every instance gracefully (stopsignal, stoptime and stop hooks), then starts numprocs fresh ones.
+    . "rolling" goes through rolling_restart(), 'restart_batch' instances at a time.
+    . Resets its retries, like start does.
+    . The instances are detached from the job and stopped concurrently off the async workers, without holding the state lock.
+*/
+pub async fn restart_program(name: &str, state: SupervisorState, rolling: bool) -> String {
+    // Detached from the job first, so the reaper doesn't restart them on its own
+    let (old, cfg) = {
+        let mut map = state.write().await;
+        let Some(job) = map.get_mut(name) else {
+            return format!("No such program: {}", name);
+        };
+        if rolling || job.config.restart_strategy == RestartStrategy::Rolling {
+            let batch = job.config.restart_batch;
+            drop(map);
+            return rolling_restart(name, state, batch).await;
+        }
+        job.stopped = false;
+        (std::mem::take(&mut job.children), job.config.clone())
+    };
+
+    let stops: Vec<_> = old.into_iter().map(|(num, pid)| {
+        let (prog, prog_cfg) = (name.to_string(), cfg.clone());
+        tokio::task::spawn_blocking(move || stop_and_cleanup_pid(&prog, num, pid, &prog_cfg))
+    }).collect();
+    for stop in stops {
+        let _ = stop.await;
+    }
+    if !pre_start(name, &cfg, 0).await {
+        return format!("pre_start hook of `{}` failed; restart aborted", name);
+    }
+
     let mut map = state.write().await;
+    let Some(job) = map.get_mut(name).filter(|job| job.config == cfg && !job.stopped) else {
+        return format!("Restart of `{}` aborted: the program was stopped or reconfigured meanwhile", name);
+    };
+    let pids = spawn_processes(name, &cfg);
+    watch_grace_period(name, &pids, cfg.starttime);
+
+    job.children.extend(pids);
+    job.retries_left = cfg.startretries;
+    job.fatal = None;
+    format!("Restarted `{}`", name)
+}
+
+
+
+
+
 
-    if let Some(mut job) = map.remove(name) {
-        for pid in &job.children {
-            stop_and_cleanup_pid(*pid, &job.config);
+/*
+    @@@
+    @rolling_restart();
+    . Replaces the instances of a program 'batch' at a time, in instance order: stops the old ones of the batch, then starts their replacements.
+    . Moves on to the next batch only once every new instance stayed up for 'starttime' seconds (at least 1), so most instances keep serving meanwhile.
+    . Aborts the rollout as soon as a new instance exits (or was replaced by an automatic restart) during that wait, the remaining instances keep running untouched.
+    . Also aborts if the program is stopped or reconfigured by a reload in the middle of the rollout, checked again right before each batch is spawned.
+*/
+pub async fn rolling_restart(name: &str, state: SupervisorState, batch: usize) -> String {
+    let cfg = {
+        let mut map = state.write().await;
+        let Some(job) = map.get_mut(name) else {
+            return format!("No such program: {}", name);
+        };
+        job.retries_left = job.config.startretries;
+        job.stopped = false;
+        job.fatal = None;
+        job.config.clone()
+    };
+    let batch = batch.max(1);
+    let gate = Duration::from_secs(cfg.starttime.max(1));
+    let numbers: Vec<usize> = (0..cfg.numprocs).collect();
+
+    for (done, chunk) in numbers.chunks(batch).enumerate() {
+        // Detached from the job first, so the reaper doesn't restart them on its own
+        let old: Vec<(usize, Pid)> = {
+            let mut map = state.write().await;
+            match map.get_mut(name) {
+                Some(job) if job.config == cfg && !job.stopped => {
+                    chunk.iter().filter_map(|num| job.children.remove(num).map(|pid| (*num, pid))).collect()
+                }
+                _ => return format!("Rollout of `{}` aborted: the program was stopped or reconfigured", name),
+            }
+        };
+        for (num, pid) in old {
+            let (prog, prog_cfg) = (name.to_string(), cfg.clone());
+            let _ = tokio::task::spawn_blocking(move || stop_and_cleanup_pid(&prog, num, pid, &prog_cfg)).await;
         }
-        job.children.clear();
 
-        map.insert(name.to_string(), job);
-    } else {
-        println!("No such program: {}", name);
+        if !pre_start(name, &cfg, chunk[0]).await {
+            let left = numbers.len().saturating_sub((done + 1) * batch);
+            return format!("Rollout of `{}` aborted: pre_start hook failed, {} instance(s) left untouched", name, left);
+        }
+        let new_pids = {
+            let mut map = state.write().await;
+            let Some(job) = map.get_mut(name).filter(|job| job.config == cfg && !job.stopped) else {
+                return format!("Rollout of `{}` aborted: the program was stopped or reconfigured meanwhile", name);
+            };
+            let new_pids = spawn_instances(name, &cfg, chunk.iter().copied());
+            job.children.extend(new_pids.clone());
+            new_pids
+        };
+        watch_grace_period(name, &new_pids, cfg.starttime);
+        tracing::info!(program = name, instances = ?chunk, "Rolling restart: waiting for new instances");
+        tokio::time::sleep(gate).await;
+
+        let map = state.read().await;
+        if map.get(name).is_none_or(|job| job.config != cfg || job.stopped) {
+            return format!("Rollout of `{}` aborted: the program was stopped or reconfigured meanwhile", name);
+        }
+        let current = map.get(name).map(|job| &job.children);
+        let failed = chunk.iter().find(|num| {
+            let pid = new_pids.get(num);
+            pid.is_none()
+                || current.and_then(|children| children.get(num)) != pid
+                || pid.and_then(|pid| procfs::read_stat(*pid)).is_none_or(|stat| stat.state == 'Z')
+        });
+        if let Some(num) = failed {
+            let left = numbers.len().saturating_sub((done + 1) * batch);
+            tracing::warn!(program = name, instance = num, "Rolling restart aborted");
+            return format!(
+                "Rollout of `{}` aborted: instance {} didn't stay up for {}s, {} instance(s) left untouched",
+                name, num, gate.as_secs(), left,
+            );
+        }
+    }
+    format!("Restarted `{}` instance by instance ({} at a time)", name, batch)
+}
+
+
+
+
+
+
+/*
+    @@@
+    @status_report();
+    . Lists every known program with its number of running instances.
+    . With 'origins' (status --verbose), also shows the config file each program comes from.
+    . A program with an instance out of retries says FATAL and why.
+*/
+pub fn status_report(map: &HashMap<String, RuntimeJob>, origins: Option<&HashMap<String, String>>) -> String {
+    let mut names: Vec<&String> = map.keys().collect();
+    names.sort();
+    names
+        .into_iter()
+        .map(|name| {
+            let job = &map[name];
+            let fatal = job.fatal.as_ref().map(|reason| format!(", FATAL: {}", reason)).unwrap_or_default();
+            match origins.and_then(|o| o.get(name)) {
+                Some(origin) => format!("{} : {} instance(s){} ({})\n", name, job.children.len(), fatal, origin),
+                None => format!("{} : {} instance(s){}\n", name, job.children.len(), fatal),
+            }
+        })
+        .collect()
+}
+
+
+
+
+
+
+/*
+    @@@
+    @reload_config();
+    . Re-reads the config file the supervisor was started with and applies it to the running state.
+    . Keeps the previous config if the file can't be parsed.
+    . Logs the options skipped in supervisord INI files as warnings.
+*/
+pub async fn reload_config(ctx: &Context) -> String {
+    // Box<dyn Error> isn't Send, keep only its message across the awaits below
+    match parser(&ctx.config_path).map_err(|e| e.to_string()) {
+        Ok(new_cfg) => {
+            let diff = config_diff(&*ctx.config.read().await, &new_cfg);
+            tracing::info!(config = %ctx.config_path, "Reloading config: {}", diff);
+            for note in &new_cfg.notes {
+                tracing::warn!("{}", note);
+            }
+            apply_config(&new_cfg, ctx.state.clone()).await;
+            *ctx.config.write().await = new_cfg;
+            "Configuration reloaded".to_string()
+        }
+        Err(e) => {
+            tracing::warn!("Failed to reload {}: {}", ctx.config_path, e);
+            format!("Failed to reload config: {}", e)
+        }
+    }
+}
+
+
+
+
+
+
+/*
+    @@@
+    @config_diff();
+    . Summarizes what a reload changes: programs added, removed and changed (with the names of their changed settings).
+*/
+pub fn config_diff(old: &Config, new: &Config) -> String {
+    let mut added: Vec<&String> = new.programs.keys().filter(|n| !old.programs.contains_key(*n)).collect();
+    let mut removed: Vec<&String> = old.programs.keys().filter(|n| !new.programs.contains_key(*n)).collect();
+    let mut changed: Vec<String> = new.programs.iter()
+        .filter_map(|(name, cfg)| {
+            let old_cfg = old.programs.get(name).filter(|old_cfg| *old_cfg != cfg)?;
+            let (old_value, new_value) = (serde_yaml::to_value(old_cfg).ok()?, serde_yaml::to_value(cfg).ok()?);
+            let keys: Vec<String> = new_value.as_mapping()?.iter()
+                .filter(|(key, value)| old_value.get(*key) != Some(*value))
+                .filter_map(|(key, _)| key.as_str().map(String::from))
+                .collect();
+            Some(format!("{} ({})", name, keys.join(", ")))
+        })
+        .collect();
+    added.sort();
+    removed.sort();
+    changed.sort();
+
+    if added.is_empty() && removed.is_empty() && changed.is_empty() {
+        return "no program changed".to_string();
+    }
+    let list = |names: Vec<String>| if names.is_empty() { "-".to_string() } else { names.join(", ") };
+    format!(
+        "added: {}; removed: {}; changed: {}",
+        list(added.into_iter().cloned().collect()),
+        list(removed.into_iter().cloned().collect()),
+        list(changed),
+    )
+}
+
+
+
+
+
+
+/*
+    @@@
+    @execute();
+    . Runs one textual command --status, start, stop, restart, reload, stats or self-upgrade-- and returns its output.
+    . Used by front-ends that can't print to the supervisor's terminal, like the control socket.
+*/
+pub async fn execute(ctx: &Context, line: &str) -> String {
+    let mut words = line.split_whitespace();
+    let cmd = words.next().unwrap_or("");
+    let args: Vec<&str> = words.collect();
+
+    match (cmd, args.as_slice()) {
+        ("status", []) => status_report(&*ctx.state.read().await, None),
+        ("status", ["--verbose"]) => {
+            let origins = ctx.config.read().await.origins.clone();
+            status_report(&*ctx.state.read().await, Some(&origins))
+        }
+        ("start", [name]) => {
+            let programs = ctx.config.read().await.programs.clone();
+            start_program(name, &programs, ctx.state.clone()).await + "\n"
+        }
+        ("stop", [name]) => stop_program(name, ctx.state.clone()).await + "\n",
+        ("restart", [name]) => restart_program(name, ctx.state.clone(), false).await + "\n",
+        ("restart", ["--rolling", name]) => restart_program(name, ctx.state.clone(), true).await + "\n",
+        ("reload", []) => reload_config(ctx).await + "\n",
+        ("self-upgrade", []) => reexec::prepare().await,
+        ("stats", args) => {
+            let tree = args.contains(&"--tree");
+            let prog = args.iter().find(|a| !a.starts_with("--")).copied();
+            stats::render_stats(&*ctx.state.read().await, prog, tree)
+        }
+        _ => format!("Unknown command: {}\n", line.trim()),
     }
 }
\ No newline at end of file
@@ -0,0 +1,88 @@
+use nix::errno::Errno;
+use nix::fcntl::{Flock, FlockArg};
+use nix::unistd::{dup2, fork, setsid, ForkResult};
+use libc::{STDERR_FILENO, STDIN_FILENO, STDOUT_FILENO};
+use nix::libc;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+
+// Locked pidfile, the lock is released when the supervisor process exits
+pub type PidFile = Flock<File>;
+
+
+
+
+
+
+/*
+    @@@
+    @lock_pidfile();
+    . Opens (or creates) the pidfile and takes an exclusive, non-blocking flock on it.
+    . Fails with the pid of the running supervisor if another one already holds the lock.
+    . The lock belongs to the open file description, so it survives the forks of daemonize().
+*/
+pub fn lock_pidfile(path: &str) -> Result<PidFile, Box<dyn std::error::Error>> {
+    if let Some(dir) = Path::new(path).parent() {
+        std::fs::create_dir_all(dir).ok();
+    }
+    let file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path)?;
+
+    match Flock::lock(file, FlockArg::LockExclusiveNonblock) {
+        Ok(lock) => Ok(lock),
+        Err((mut file, Errno::EWOULDBLOCK)) => {
+            let mut pid = String::new();
+            let _ = file.read_to_string(&mut pid);
+            Err(format!("another supervisor is already running (pid {}, pidfile {})", pid.trim(), path).into())
+        }
+        Err((_, errno)) => Err(format!("cannot lock pidfile {}: {}", path, errno).into()),
+    }
+}
+
+
+
+
+
+
+/*
+    @@@
+    @write_pid();
+    . Replaces the content of the locked pidfile with the current pid.
+*/
+pub fn write_pid(pidfile: &mut PidFile) -> std::io::Result<()> {
+    pidfile.set_len(0)?;
+    pidfile.rewind()?;
+    writeln!(pidfile, "{}", std::process::id())?;
+    pidfile.flush()
+}
+
+
+
+
+
+
+/*
+    @@@
+    @daemonize();
+    . Double-forks: the first child becomes a session leader (setsid()) and the grandchild can never reacquire a terminal.
+    . Both parents exit immediately, the grandchild keeps the working directory so relative config paths still resolve.
+    . Points stdin, stdout and stderr to /dev/null, the supervisor log and the control socket replace them.
+    . Must run before the tokio runtime is built, forking a multi-threaded process only keeps the calling thread.
+*/
+pub fn daemonize() -> Result<(), Box<dyn std::error::Error>> {
+    if let ForkResult::Parent { .. } = unsafe { fork()? } {
+        std::process::exit(0);
+    }
+    setsid()?;
+    if let ForkResult::Parent { .. } = unsafe { fork()? } {
+        std::process::exit(0);
+    }
+
+    let devnull = OpenOptions::new().read(true).write(true).open("/dev/null")?;
+    for fd in [STDIN_FILENO, STDOUT_FILENO, STDERR_FILENO] {
+        dup2(devnull.as_raw_fd(), fd)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,136 @@
+use crate::parse::ProgramConfig;
+use crate::secrets;
+use std::collections::BTreeMap;
+use std::ffi::CString;
+use std::io;
+
+
+
+
+
+
+/*
+    @@@
+    @parse_dotenv();
+    . Parses dotenv text: KEY=value lines, an optional leading 'export', '#' comments and blank lines.
+    . Single-quoted values are literal, double-quoted ones understand \n, \t, \" and \\, unquoted ones end at ' #'.
+    . Returns the line number of the first malformed line.
+*/
+pub fn parse_dotenv(text: &str) -> Result<Vec<(String, String)>, usize> {
+    let mut vars = Vec::new();
+
+    for (idx, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").unwrap_or(line).trim_start();
+        let (key, value) = line.split_once('=').ok_or(idx + 1)?;
+        let key = key.trim();
+        if key.is_empty() || !key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            return Err(idx + 1);
+        }
+
+        let value = value.trim();
+        let value = if let Some(quoted) = value.strip_prefix('\'') {
+            quoted.strip_suffix('\'').ok_or(idx + 1)?.to_string()
+        } else if let Some(quoted) = value.strip_prefix('"') {
+            let quoted = quoted.strip_suffix('"').ok_or(idx + 1)?;
+            let mut out = String::with_capacity(quoted.len());
+            let mut chars = quoted.chars();
+            while let Some(c) = chars.next() {
+                match c {
+                    '\\' => match chars.next() {
+                        Some('n') => out.push('\n'),
+                        Some('t') => out.push('\t'),
+                        Some(other) => out.push(other),
+                        None => out.push('\\'),
+                    },
+                    c => out.push(c),
+                }
+            }
+            out
+        } else {
+            value.split(" #").next().unwrap_or("").trim_end().to_string()
+        };
+        vars.push((key.to_string(), value));
+    }
+    Ok(vars)
+}
+
+
+
+
+
+
+/*
+    @@@
+    @build_env();
+    . Builds the environment of one instance, read at spawn time so rotated env files are picked up on restart.
+    . Starts from the supervisor's environment, only the 'pass_env' names when it is set, or nothing but them with 'clear_env'.
+    . Then layers each 'env_file' in order, the 'env' map, the 'secrets' files and finally SUPERVISOR_PROGRAM/SUPERVISOR_PROCESS_NUM.
+    . A secret file that can't be read or is world-readable fails the whole environment.
+*/
+pub fn build_env(name: &str, cfg: &ProgramConfig, instance: usize) -> io::Result<Vec<(String, String)>> {
+    let mut env: BTreeMap<String, String> = match (cfg.clear_env, cfg.pass_env.is_empty()) {
+        (false, true) => std::env::vars().collect(),
+        _ => std::env::vars().filter(|(k, _)| cfg.pass_env.contains(k)).collect(),
+    };
+
+    for path in &cfg.env_file {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| io::Error::new(e.kind(), format!("env_file {}: {}", path, e)))?;
+        let vars = parse_dotenv(&text).map_err(|line| {
+            io::Error::new(io::ErrorKind::InvalidData, format!("env_file {}: malformed line {}", path, line))
+        })?;
+        env.extend(vars);
+    }
+    if let Some(vars) = &cfg.env {
+        env.extend(vars.iter().map(|(k, v)| (k.clone(), v.clone())));
+    }
+    for (key, path) in &cfg.secrets {
+        let value = secrets::read_secret(path)
+            .map_err(|e| io::Error::new(e.kind(), format!("secret {}: {}", key, e)))?;
+        env.insert(key.clone(), value);
+    }
+    env.insert("SUPERVISOR_PROGRAM".to_string(), name.to_string());
+    env.insert("SUPERVISOR_PROCESS_NUM".to_string(), instance.to_string());
+
+    Ok(env.into_iter().collect())
+}
+
+// KEY=value strings for execvpe(), entries with a NUL byte can't be passed and are dropped
+pub fn to_cstrings(env: &[(String, String)]) -> Vec<CString> {
+    env.iter().filter_map(|(k, v)| CString::new(format!("{}={}", k, v)).ok()).collect()
+}
+
+
+
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_dotenv_quoting_and_comments() {
+        let text = "# comment\n\nexport A=1\nB = two words # trailing\nC='literal \\n #'\nD=\"line\\nnext \\\"q\\\"\"\nE=\n";
+        let vars = parse_dotenv(text).unwrap();
+        let expected = [
+            ("A", "1"),
+            ("B", "two words"),
+            ("C", "literal \\n #"),
+            ("D", "line\nnext \"q\""),
+            ("E", ""),
+        ];
+        assert_eq!(vars, expected.map(|(k, v)| (k.to_string(), v.to_string())));
+    }
+
+    #[test]
+    fn parse_dotenv_reports_the_malformed_line() {
+        assert_eq!(parse_dotenv("A=1\nnot a pair\n"), Err(2));
+        assert_eq!(parse_dotenv("A=1\n\nB-C=2\n"), Err(3));
+        assert_eq!(parse_dotenv("A='open\n"), Err(1));
+    }
+}
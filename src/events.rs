@@ -0,0 +1,266 @@
+use std::collections::VecDeque;
+use std::os::fd::OwnedFd;
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+use nix::unistd::Pid;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::unix::pipe;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::time::{interval, Duration};
+use tracing::{info, warn};
+
+
+// Process states as named by supervisord, used in PROCESS_STATE_* event names
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProcessState {
+    Stopped,
+    Starting,
+    Running,
+    Backoff,
+    Stopping,
+    Exited,
+    Fatal,
+}
+
+impl ProcessState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ProcessState::Stopped  => "STOPPED",
+            ProcessState::Starting => "STARTING",
+            ProcessState::Running  => "RUNNING",
+            ProcessState::Backoff  => "BACKOFF",
+            ProcessState::Stopping => "STOPPING",
+            ProcessState::Exited   => "EXITED",
+            ProcessState::Fatal    => "FATAL",
+        }
+    }
+}
+
+// A single notification broadcast to every event listener
+#[derive(Debug, Clone)]
+pub struct Event {
+    pub name: String,
+    pub serial: u64,
+    pub payload: String,
+}
+
+static BUS: OnceLock<broadcast::Sender<Event>> = OnceLock::new();
+static SERIAL: AtomicU64 = AtomicU64::new(0);
+// Listeners being served; their pipes aren't handed over by a self-upgrade, see reexec::prepare()
+static ATTACHED: AtomicUsize = AtomicUsize::new(0);
+
+// Counts a listener in ATTACHED for as long as serve_listener() runs
+struct Attachment;
+
+impl Attachment {
+    fn new() -> Self {
+        ATTACHED.fetch_add(1, Ordering::Relaxed);
+        Attachment
+    }
+}
+
+impl Drop for Attachment {
+    fn drop(&mut self) {
+        ATTACHED.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+pub fn attached_listeners() -> usize {
+    ATTACHED.load(Ordering::Relaxed)
+}
+
+fn bus() -> &'static broadcast::Sender<Event> {
+    BUS.get_or_init(|| broadcast::channel(1024).0)
+}
+
+
+
+
+
+
+/*
+    @@@
+    @emit();
+    . Stamps the event with the next global serial and broadcasts it to every listener task.
+    . Sending without any subscriber is not an error, the event is simply dropped.
+*/
+pub fn emit(name: &str, payload: String) {
+    let serial = SERIAL.fetch_add(1, Ordering::Relaxed) + 1;
+    let _ = bus().send(Event { name: name.to_string(), serial, payload });
+}
+
+
+
+
+
+
+/*
+    @@@
+    @process_state();
+    . Emits a PROCESS_STATE_<to> event with supervisord's `processname groupname from_state pid` payload.
+    . Extra key:value tokens (e.g. `expected:1`) are appended as given.
+*/
+pub fn process_state(name: &str, pid: Pid, from: ProcessState, to: ProcessState, extra: &str) {
+    let mut payload = format!(
+        "processname:{} groupname:{} from_state:{} pid:{}",
+        name, name, from.as_str(), pid
+    );
+    if !extra.is_empty() {
+        payload.push(' ');
+        payload.push_str(extra);
+    }
+    emit(&format!("PROCESS_STATE_{}", to.as_str()), payload);
+}
+
+
+
+
+
+
+/*
+    @@@
+    @run_ticker();
+    . Emits TICK_5 every five seconds, and TICK_60 / TICK_3600 whenever the wall clock crosses those boundaries.
+    . The payload is supervisord's `when:<unix timestamp>`.
+*/
+pub async fn run_ticker() {
+    let mut ticker = interval(Duration::from_secs(5));
+    let mut last: u64 = 0;
+
+    loop {
+        ticker.tick().await;
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+
+        for period in [5, 60, 3600] {
+            if last == 0 || now / period != last / period {
+                emit(&format!("TICK_{}", period), format!("when:{}", now));
+            }
+        }
+        last = now;
+    }
+}
+
+
+
+
+
+
+/*
+    @@@
+    @is_subscribed();
+    . An event matches a subscription when the names are equal or the subscription is one of its parent types.
+    . 'EVENT' matches everything, 'PROCESS_STATE' matches every PROCESS_STATE_* and 'TICK' every TICK_*.
+*/
+fn is_subscribed(event: &str, subscriptions: &[String]) -> bool {
+    subscriptions.iter().any(|sub| {
+        let sub = sub.trim().to_uppercase();
+        sub == "EVENT" || event == sub || event.starts_with(&format!("{}_", sub))
+    })
+}
+
+
+
+
+
+
+/*
+    @@@
+    @serve_listener();
+    . Speaks supervisord's listener protocol over the child's stdin/stdout pipes.
+    . Waits for 'READY', writes a header line plus payload, then reads back 'RESULT <len>' and its body (OK or FAIL).
+    . Events arriving while the listener is busy are kept in a buffer of 'buffer_size' entries, the oldest one is dropped on overflow.
+    . A FAIL result puts the event back at the front of the buffer, so it is delivered again on the next READY.
+*/
+pub async fn serve_listener(
+    name: String,
+    pid: Pid,
+    stdin: OwnedFd,
+    stdout: OwnedFd,
+    subscriptions: Vec<String>,
+    buffer_size: usize,
+) {
+    let _attachment = Attachment::new();
+    let (mut writer, reader) = match (pipe::Sender::from_owned_fd(stdin), pipe::Receiver::from_owned_fd(stdout)) {
+        (Ok(w), Ok(r)) => (w, r),
+        (Err(e), _) | (_, Err(e)) => {
+            warn!(program = %name, pid = pid.as_raw(), "Failed to attach event listener pipes: {}", e);
+            return;
+        }
+    };
+    let mut lines = BufReader::new(reader).lines();
+    let mut rx = bus().subscribe();
+    let mut buffer: VecDeque<Event> = VecDeque::with_capacity(buffer_size);
+    let mut pool_serial: u64 = 0;
+    let mut ready = false;
+
+    info!(program = %name, pid = pid.as_raw(), "Event listener attached");
+
+    loop {
+        if ready {
+            if let Some(event) = buffer.pop_front() {
+                ready = false;
+                pool_serial += 1;
+                let message = format!(
+                    "ver:3.0 server:supervisor serial:{} pool:{} poolserial:{} eventname:{} len:{}\n{}",
+                    event.serial, name, pool_serial, event.name, event.payload.len(), event.payload
+                );
+                if writer.write_all(message.as_bytes()).await.is_err() {
+                    break;
+                }
+
+                let header = match lines.next_line().await {
+                    Ok(Some(line)) => line,
+                    _ => break,
+                };
+                let len = header
+                    .strip_prefix("RESULT ")
+                    .and_then(|n| n.trim().parse::<usize>().ok());
+                let Some(len) = len else {
+                    warn!(program = %name, pid = pid.as_raw(), "Malformed result header: {:?}", header);
+                    buffer.push_front(event);
+                    continue;
+                };
+
+                let mut body = vec![0u8; len];
+                if lines.get_mut().read_exact(&mut body).await.is_err() {
+                    break;
+                }
+                if body != b"OK" {
+                    warn!(program = %name, serial = event.serial, "Listener rejected event; requeued");
+                    buffer.push_front(event);
+                }
+                continue;
+            }
+        }
+
+        tokio::select! {
+            line = lines.next_line() => match line {
+                Ok(Some(line)) if line.trim() == "READY" => ready = true,
+                Ok(Some(line)) => warn!(program = %name, pid = pid.as_raw(), "Unexpected listener output: {:?}", line),
+                _ => break,
+            },
+            event = rx.recv() => match event {
+                Ok(event) if is_subscribed(&event.name, &subscriptions) => {
+                    buffer.push_back(event);
+                    if buffer.len() > buffer_size {
+                        let dropped = buffer.pop_front();
+                        warn!(
+                            program = %name,
+                            serial = dropped.map(|e| e.serial).unwrap_or_default(),
+                            "Event buffer overflowed; discarding oldest event"
+                        );
+                    }
+                }
+                Ok(_) => {}
+                Err(RecvError::Lagged(n)) => warn!(program = %name, "Event listener lagged; {} events lost", n),
+                Err(RecvError::Closed) => break,
+            },
+        }
+    }
+
+    info!(program = %name, pid = pid.as_raw(), "Event listener detached");
+}
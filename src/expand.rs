@@ -0,0 +1,215 @@
+use crate::parse::ProgramConfig;
+use serde_yaml::{Mapping, Value};
+
+
+// Program keys whose string values go through ${VAR} interpolation
+const EXPANDED_KEYS: [&str; 9] = ["cmd", "args", "workingdir", "stdout", "stderr", "env", "env_file", "secrets", "sockets"];
+
+
+
+
+
+
+/*
+    @@@
+    @expand();
+    . Replaces ${VAR}, ${VAR:-default} and ${VAR:?message} with values from the supervisor's environment.
+    . ${VAR} becomes empty when VAR is unset, ${VAR:-default} uses 'default' when VAR is unset or empty.
+    . ${VAR:?message} fails with 'message' when VAR is unset or empty, so a missing setting stops the config from loading.
+    . $${ escapes a literal ${, a lone $ (e.g. "$HOME" in a shell command) is left untouched.
+*/
+pub fn expand(input: &str) -> Result<String, String> {
+    let mut out = String::with_capacity(input.len());
+    let mut rest = input;
+
+    while let Some(pos) = rest.find('$') {
+        out.push_str(&rest[..pos]);
+        rest = &rest[pos..];
+
+        if rest.starts_with("$${") {
+            out.push_str("${");
+            rest = &rest[3..];
+            continue;
+        }
+        if !rest.starts_with("${") {
+            out.push('$');
+            rest = &rest[1..];
+            continue;
+        }
+
+        let end = rest.find('}').ok_or_else(|| format!("unterminated `${{` in `{}`", input))?;
+        let expr = &rest[2..end];
+        rest = &rest[end + 1..];
+
+        let (name, op, arg) = match expr.find(":-").or_else(|| expr.find(":?")) {
+            Some(i) => (&expr[..i], &expr[i..i + 2], &expr[i + 2..]),
+            None => (expr, "", ""),
+        };
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            return Err(format!("invalid variable name `{}` in `{}`", name, input));
+        }
+
+        let value = std::env::var(name).ok().filter(|v| !v.is_empty());
+        match (value, op) {
+            (Some(value), _) => out.push_str(&value),
+            (None, ":-") => out.push_str(arg),
+            (None, ":?") if arg.is_empty() => return Err(format!("{} is not set", name)),
+            (None, ":?") => return Err(format!("{}: {}", name, arg)),
+            (None, _) => {}
+        }
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+
+
+
+
+
+/*
+    @@@
+    @expand_program();
+    . Runs expand() over the string values of cmd, args, workingdir, stdout, stderr, env, env_file, secrets and sockets of one program, in place.
+    . Returns the key and the message of every value that failed, the other values are still expanded.
+*/
+pub fn expand_program(program: &mut Mapping) -> Vec<(String, String)> {
+    let mut errors = Vec::new();
+
+    for key in EXPANDED_KEYS {
+        let Some(value) = program.get_mut(key) else { continue };
+        let strings: Vec<&mut String> = match value {
+            Value::String(s) => vec![s],
+            Value::Sequence(items) => items.iter_mut().filter_map(|v| match v {
+                Value::String(s) => Some(s),
+                _ => None,
+            }).collect(),
+            Value::Mapping(map) => map.values_mut().filter_map(|v| match v {
+                Value::String(s) => Some(s),
+                _ => None,
+            }).collect(),
+            _ => Vec::new(),
+        };
+        for s in strings {
+            match expand(s) {
+                Ok(expanded) => *s = expanded,
+                Err(e) => errors.push((key.to_string(), e)),
+            }
+        }
+    }
+    errors
+}
+
+
+
+
+
+
+/*
+    @@@
+    @expand_instance();
+    . Replaces the per-instance placeholders {program}, {instance} and {instance+N} (e.g. {instance+8000} for a port).
+    . A number can be zero-padded with ':0W', e.g. {instance:02} gives 00, 01, ...
+    . Instances are numbered from 0 to numprocs - 1, other {...} sequences are left untouched.
+*/
+pub fn expand_instance(input: &str, program: &str, instance: usize) -> String {
+    let mut out = String::with_capacity(input.len());
+    let mut rest = input;
+
+    while let Some(start) = rest.find('{') {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let Some(end) = rest.find('}') else { break };
+
+        let placeholder = rest[1..end].replace(' ', "");
+        let (name, width) = match placeholder.split_once(":0") {
+            Some((name, width)) => (name, width.parse::<usize>().ok()),
+            None => (placeholder.as_str(), Some(0)),
+        };
+        let number = match name {
+            "instance" => Some(instance),
+            p => p.strip_prefix("instance+")
+                .and_then(|offset| offset.parse::<usize>().ok())
+                .map(|offset| instance + offset),
+        };
+        let value = match (name, number, width) {
+            ("program", _, Some(0)) => Some(program.to_string()),
+            (_, Some(number), Some(width)) => Some(format!("{:0width$}", number, width = width)),
+            _ => None,
+        };
+        match value {
+            Some(value) => {
+                out.push_str(&value);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                out.push('{');
+                rest = &rest[1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+
+
+
+
+
+/*
+    @@@
+    @instance_config();
+    . Returns the config of one instance of a program: args, env values, env_file, secrets, stdout, stderr and workingdir with their placeholders replaced.
+*/
+pub fn instance_config(name: &str, cfg: &ProgramConfig, instance: usize) -> ProgramConfig {
+    let expand = |s: &String| expand_instance(s, name, instance);
+    ProgramConfig {
+        args: cfg.args.iter().map(expand).collect(),
+        env: cfg.env.as_ref().map(|env| env.iter().map(|(k, v)| (k.clone(), expand(v))).collect()),
+        env_file: cfg.env_file.iter().map(expand).collect(),
+        secrets: cfg.secrets.iter().map(|(k, path)| (k.clone(), expand(path))).collect(),
+        stdout: cfg.stdout.as_ref().map(expand),
+        stderr: cfg.stderr.as_ref().map(expand),
+        workingdir: cfg.workingdir.as_ref().map(expand),
+        ..cfg.clone()
+    }
+}
+
+
+
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expand_defaults_escapes_and_required() {
+        std::env::set_var("EXPAND_TEST_SET", "value");
+        std::env::set_var("EXPAND_TEST_EMPTY", "");
+        std::env::remove_var("EXPAND_TEST_UNSET");
+
+        assert_eq!(expand("a ${EXPAND_TEST_SET} b").unwrap(), "a value b");
+        assert_eq!(expand("${EXPAND_TEST_UNSET}").unwrap(), "");
+        assert_eq!(expand("${EXPAND_TEST_SET:-other}").unwrap(), "value");
+        assert_eq!(expand("${EXPAND_TEST_UNSET:-other}").unwrap(), "other");
+        assert_eq!(expand("${EXPAND_TEST_EMPTY:-other}").unwrap(), "other");
+        assert_eq!(expand("${EXPAND_TEST_SET:?missing}").unwrap(), "value");
+        assert_eq!(expand("${EXPAND_TEST_UNSET:?}").unwrap_err(), "EXPAND_TEST_UNSET is not set");
+        assert_eq!(expand("${EXPAND_TEST_EMPTY:?needed}").unwrap_err(), "EXPAND_TEST_EMPTY: needed");
+        assert_eq!(expand("$${EXPAND_TEST_SET} $HOME $").unwrap(), "${EXPAND_TEST_SET} $HOME $");
+        assert!(expand("${EXPAND_TEST_SET").is_err());
+        assert!(expand("${BAD-NAME}").is_err());
+    }
+
+    #[test]
+    fn expand_instance_placeholders() {
+        assert_eq!(expand_instance("{program}-{instance}", "web", 2), "web-2");
+        assert_eq!(expand_instance("port {instance+8000}", "web", 3), "port 8003");
+        assert_eq!(expand_instance("{instance:02}/{instance+1:03}", "web", 7), "07/008");
+        assert_eq!(expand_instance("{other} {instance} {", "web", 1), "{other} 1 {");
+        assert_eq!(expand_instance("{program:02}", "web", 1), "{program:02}");
+    }
+}
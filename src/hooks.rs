@@ -0,0 +1,328 @@
+use crate::secrets;
+use crate::output;
+use crate::expand::instance_config;
+use crate::parse::ProgramConfig;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::os::unix::process::CommandExt;
+use std::process::{Command, Stdio};
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+use nix::sys::signal::{killpg, Signal};
+use nix::unistd::Pid;
+use tracing::{info, warn};
+
+
+// How long on_failure waits for the output of an instance to reach its stderr file
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+
+// Bytes read at a time from the end of a stderr log, until it holds enough lines for on_failure
+const TAIL_BLOCK: u64 = 8192;
+
+// Exit statuses of hook commands, keyed by pid.
+// The reaper (waitpid(-1)) may collect a hook before the hook runner does, so it parks the status here.
+static HOOK_PIDS: OnceLock<Mutex<HashMap<i32, Option<i32>>>> = OnceLock::new();
+
+fn hook_pids() -> &'static Mutex<HashMap<i32, Option<i32>>> {
+    HOOK_PIDS.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+// What went wrong with an instance, for on_failure
+pub enum Failure<'a> {
+    Exited { pid: Pid, exit_code: u32, signal: Option<Signal> },
+    SpawnFailed(&'a str),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum HookKind {
+    PreStart,
+    PostStart,
+    PreStop,
+    PostStop,
+}
+
+impl HookKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            HookKind::PreStart  => "pre_start",
+            HookKind::PostStart => "post_start",
+            HookKind::PreStop   => "pre_stop",
+            HookKind::PostStop  => "post_stop",
+        }
+    }
+
+    fn command<'a>(&self, cfg: &'a ProgramConfig) -> Option<&'a String> {
+        match self {
+            HookKind::PreStart  => cfg.pre_start.as_ref(),
+            HookKind::PostStart => cfg.post_start.as_ref(),
+            HookKind::PreStop   => cfg.pre_stop.as_ref(),
+            HookKind::PostStop  => cfg.post_stop.as_ref(),
+        }
+    }
+}
+
+
+
+
+
+
+/*
+    @@@
+    @claim_exit();
+    . Called by the reaper for every collected pid, stores the exit code if the pid belongs to a running hook.
+    . Returns true when the pid was a hook, so the reaper doesn't treat it as a program instance.
+*/
+pub fn claim_exit(pid: Pid, code: i32) -> bool {
+    let mut pids = hook_pids().lock().unwrap();
+    match pids.get_mut(&pid.as_raw()) {
+        Some(slot) => {
+            *slot = Some(code);
+            true
+        }
+        None => false,
+    }
+}
+
+
+
+
+
+
+/*
+    @@@
+    @run_command();
+    . Runs 'sh -c command' with the extra env vars, optionally writing 'input' to its stdin.
+    . Output is appended to 'output' (the program's stderr log, relative to 'workingdir' like the program's) or discarded.
+    . Runs it in its own process group, so a timeout kills whatever it started too (e.g. a script it runs), not only the shell.
+    . Kills the command once 'timeout' seconds elapsed, returns its exit code or None on timeout/spawn failure.
+*/
+pub fn run_command(
+    command: &str,
+    env: &[(String, String)],
+    input: Option<String>,
+    workingdir: Option<&String>,
+    output: Option<&String>,
+    timeout: u64,
+) -> Option<i32> {
+    let sink = || -> Stdio {
+        output
+            .and_then(|path| output::open_log(&output::log_path(workingdir.map(String::as_str), path)).ok())
+            .map(Stdio::from)
+            .unwrap_or_else(Stdio::null)
+    };
+
+    let mut cmd = Command::new("sh");
+    cmd.arg("-c")
+        .arg(command)
+        .envs(env.iter().map(|(k, v)| (k, v)))
+        .stdin(if input.is_some() { Stdio::piped() } else { Stdio::null() })
+        .stdout(sink())
+        .stderr(sink())
+        .process_group(0);
+    if let Some(dir) = workingdir {
+        cmd.current_dir(dir);
+    }
+
+    let mut child = {
+        let mut pids = hook_pids().lock().unwrap();
+        match cmd.spawn() {
+            Ok(child) => {
+                pids.insert(child.id() as i32, None);
+                child
+            }
+            Err(e) => {
+                warn!("Failed to run `{}`: {}", command, e);
+                return None;
+            }
+        }
+    };
+    let pid = child.id() as i32;
+
+    // Written from its own thread: a command that never reads its stdin must not keep the timeout from applying
+    if let (Some(input), Some(mut stdin)) = (input, child.stdin.take()) {
+        std::thread::spawn(move || {
+            let _ = stdin.write_all(input.as_bytes());
+        });
+    }
+
+    let deadline = Duration::from_secs(timeout);
+    let interval = Duration::from_millis(100);
+    let mut elapsed = Duration::ZERO;
+    let code = loop {
+        if let Some(Some(code)) = hook_pids().lock().unwrap().get(&pid) {
+            break Some(*code);
+        }
+        match child.try_wait() {
+            Ok(Some(status)) => break status.code().or(Some(-1)),
+            Ok(None) | Err(_) if elapsed < deadline => {
+                std::thread::sleep(interval);
+                elapsed += interval;
+            }
+            _ => {
+                warn!("`{}` timed out after {}s; killing it", command, timeout);
+                let _ = killpg(Pid::from_raw(pid), Signal::SIGKILL);
+                break None;
+            }
+        }
+    };
+
+    hook_pids().lock().unwrap().remove(&pid);
+    code
+}
+
+
+
+
+
+
+/*
+    @@@
+    @run_hook();
+    . Runs the program's hook of the given kind, if configured, within 'hook_timeout' seconds.
+    . Exports SUPERVISOR_PROGRAM, SUPERVISOR_HOOK and, when known, SUPERVISOR_PID and SUPERVISOR_EXIT_CODE.
+    . Returns false only if a configured hook failed or timed out.
+*/
+pub fn run_hook(
+    kind: HookKind,
+    name: &str,
+    cfg: &ProgramConfig,
+    pid: Option<Pid>,
+    exit_code: Option<i32>,
+) -> bool {
+    let Some(command) = kind.command(cfg) else {
+        return true;
+    };
+
+    let mut env = vec![
+        ("SUPERVISOR_PROGRAM".to_string(), name.to_string()),
+        ("SUPERVISOR_HOOK".to_string(), kind.as_str().to_string()),
+    ];
+    if let Some(pid) = pid {
+        env.push(("SUPERVISOR_PID".to_string(), pid.to_string()));
+    }
+    if let Some(code) = exit_code {
+        env.push(("SUPERVISOR_EXIT_CODE".to_string(), code.to_string()));
+    }
+
+    match run_command(command, &env, None, cfg.workingdir.as_ref(), cfg.stderr.as_ref(), cfg.hook_timeout) {
+        Some(0) => {
+            info!(program = name, hook = kind.as_str(), "Hook succeeded");
+            true
+        }
+        Some(code) => {
+            warn!(program = name, hook = kind.as_str(), exit_code = code, "Hook failed");
+            false
+        }
+        None => {
+            warn!(program = name, hook = kind.as_str(), "Hook did not complete");
+            false
+        }
+    }
+}
+
+
+
+
+
+
+/*
+    @@@
+    @tail_lines();
+    . Returns the last 'n' lines of the file at 'path', or an empty string if it can't be read.
+    . Reads backwards from the end TAIL_BLOCK bytes at a time, so a large log isn't loaded whole, invalid UTF-8 is replaced.
+*/
+fn tail_lines(path: &Path, n: usize) -> String {
+    let Ok(mut file) = File::open(path) else {
+        return String::new();
+    };
+    let Ok(mut start) = file.seek(SeekFrom::End(0)) else {
+        return String::new();
+    };
+    // n + 1 newlines make sure the n last lines are whole
+    let mut tail: Vec<u8> = Vec::new();
+    while start > 0 && tail.iter().filter(|&&b| b == b'\n').count() <= n {
+        let block = start.min(TAIL_BLOCK);
+        start -= block;
+        let mut chunk = vec![0u8; block as usize];
+        if file.seek(SeekFrom::Start(start)).is_err() || file.read_exact(&mut chunk).is_err() {
+            return String::new();
+        }
+        chunk.extend_from_slice(&tail);
+        tail = chunk;
+    }
+    let content = String::from_utf8_lossy(&tail);
+    let lines: Vec<&str> = content.lines().collect();
+    let start = lines.len().saturating_sub(n);
+    lines[start..].join("\n")
+}
+
+
+
+
+
+
+/*
+    @@@
+    @notify_failure();
+    . Runs the program's on_failure command --or the global one-- in the background when an instance crashed or went FATAL.
+    . Exports the failure details as SUPERVISOR_* env vars and writes them, followed by the last 'on_failure_lines' lines of stderr (secrets redacted), to its stdin.
+    . An instance that couldn't even be spawned has no pid nor exit code, SUPERVISOR_ERROR tells why instead.
+    . The stderr file is the one of that instance, placeholders replaced and relative to its workingdir.
+    . With 'reopen_logs', waits for the output still in the instance's pipes to reach the file first (up to DRAIN_TIMEOUT).
+*/
+pub fn notify_failure(
+    name: &str,
+    cfg: &ProgramConfig,
+    instance: usize,
+    failure: Failure,
+    fatal: bool,
+) {
+    let Some(command) = cfg.on_failure.clone() else {
+        return;
+    };
+
+    let state = if fatal { "FATAL" } else { "EXITED" };
+    let mut env = vec![
+        ("SUPERVISOR_PROGRAM".to_string(), name.to_string()),
+        ("SUPERVISOR_STATE".to_string(), state.to_string()),
+    ];
+    let mut input = format!("program: {}\n", name);
+    let exited = match failure {
+        Failure::Exited { pid, exit_code, signal } => {
+            env.push(("SUPERVISOR_PID".to_string(), pid.to_string()));
+            env.push(("SUPERVISOR_EXIT_CODE".to_string(), exit_code.to_string()));
+            input.push_str(&format!("pid: {}\nexit_code: {}\nstate: {}\n", pid, exit_code, state));
+            if let Some(sig) = signal {
+                env.push(("SUPERVISOR_SIGNAL".to_string(), sig.as_str().to_string()));
+                input.push_str(&format!("signal: {}\n", sig.as_str()));
+            }
+            Some(pid)
+        }
+        Failure::SpawnFailed(reason) => {
+            env.push(("SUPERVISOR_ERROR".to_string(), reason.to_string()));
+            input.push_str(&format!("state: {}\nerror: {}\n", state, reason));
+            None
+        }
+    };
+    let instance_cfg = instance_config(name, cfg, instance);
+    let stderr = instance_cfg.stderr.as_ref().map(|path| output::log_path(instance_cfg.workingdir.as_deref(), path));
+
+    let (prog, workingdir, timeout, lines) = (name.to_string(), cfg.workingdir.clone(), cfg.hook_timeout, cfg.on_failure_lines);
+    tokio::task::spawn_blocking(move || {
+        if let Some(path) = stderr {
+            // With 'reopen_logs', the last lines may still be on their way through the pipe
+            if let Some(pid) = exited {
+                output::wait_drained(pid, DRAIN_TIMEOUT);
+            }
+            input.push('\n');
+            input.push_str(&secrets::redact(&tail_lines(&path, lines)));
+            input.push('\n');
+        }
+        match run_command(&command, &env, Some(input), workingdir.as_ref(), None, timeout) {
+            Some(0) => info!(program = %prog, "on_failure command succeeded"),
+            code => warn!(program = %prog, exit_code = ?code, "on_failure command failed"),
+        }
+    });
+}
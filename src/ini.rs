@@ -0,0 +1,548 @@
+use crate::validate::{ConfigError, Problem};
+use serde_yaml::{Mapping, Value};
+use std::collections::HashMap;
+use std::path::Path;
+
+
+// supervisord options copied as they are, with their name in ProgramConfig
+const RENAMED: [(&str, &str); 12] = [
+    ("directory", "workingdir"),
+    ("umask", "umask"),
+    ("numprocs", "numprocs"),
+    ("autostart", "autostart"),
+    ("startsecs", "starttime"),
+    ("startretries", "startretries"),
+    ("stopsignal", "stopsignal"),
+    ("stopwaitsecs", "stoptime"),
+    ("stdout_logfile", "stdout"),
+    ("stderr_logfile", "stderr"),
+    ("events", "events"),
+    ("buffer_size", "buffer_size"),
+];
+
+// supervisord options without an equivalent, skipped with a note
+const IGNORED: [&str; 16] = [
+    "priority", "process_name", "stopasgroup", "killasgroup", "serverurl", "result_handler",
+    "stdout_logfile_maxbytes", "stdout_logfile_backups", "stdout_capture_maxbytes", "stdout_events_enabled",
+    "stdout_syslog", "stderr_logfile_maxbytes", "stderr_logfile_backups", "stderr_capture_maxbytes",
+    "stderr_events_enabled", "stderr_syslog",
+];
+
+// One [section] of the file, with its 'key = value' entries and their line numbers
+struct Section {
+    name: String,
+    line: usize,
+    entries: Vec<(String, String, usize)>,
+}
+
+// Values available to %(name)s expansions in a program section
+struct Vars<'a> {
+    program: &'a str,
+    group: &'a str,
+    here: String,
+    numprocs: usize,
+    numprocs_start: usize,
+}
+
+
+
+
+
+
+/*
+    @@@
+    @sections();
+    . Splits INI text into sections, the way supervisord's config parser reads it.
+    . Lines starting with ';' or '#' are comments, so is the end of a line from ' ;'.
+    . 'key = value' (or 'key: value') entries, indented lines continue the previous value.
+*/
+fn sections(text: &str) -> Result<Vec<Section>, ConfigError> {
+    let mut sections: Vec<Section> = Vec::new();
+
+    for (idx, raw) in text.lines().enumerate() {
+        let line = idx + 1;
+        let content = raw.split(" ;").next().unwrap_or("").trim_end();
+        let trimmed = content.trim_start();
+        if trimmed.is_empty() || trimmed.starts_with(';') || trimmed.starts_with('#') {
+            continue;
+        }
+
+        if trimmed.len() < content.len() {
+            if let Some((_, value, _)) = sections.last_mut().and_then(|s| s.entries.last_mut()) {
+                value.push('\n');
+                value.push_str(trimmed);
+                continue;
+            }
+        }
+        if let Some(name) = trimmed.strip_prefix('[').and_then(|t| t.strip_suffix(']')) {
+            sections.push(Section { name: name.trim().to_string(), line, entries: Vec::new() });
+            continue;
+        }
+
+        let Some(split) = trimmed.find(['=', ':']) else {
+            return Err(ConfigError::new(Some((line, 1)), format!("expected `key = value`, found `{}`", trimmed)));
+        };
+        let Some(section) = sections.last_mut() else {
+            return Err(ConfigError::new(Some((line, 1)), "option outside of a [section]".to_string()));
+        };
+        let (key, value) = (trimmed[..split].trim(), trimmed[split + 1..].trim());
+        section.entries.push((key.to_lowercase(), value.to_string(), line));
+    }
+    Ok(sections)
+}
+
+
+
+
+
+
+/*
+    @@@
+    @expand();
+    . Replaces supervisord's %(name)s expansions with their value, or with the equivalent placeholder when it differs per instance.
+    . %(program_name)s becomes the program's name, %(process_num)02d becomes {instance:02} ({instance+N:02} with numprocs_start = N).
+    . %(ENV_X)s becomes ${X}, resolved when the config is loaded, %(here)s the directory of the file and %% a single %.
+*/
+fn expand(value: &str, vars: &Vars) -> Result<String, String> {
+    let mut out = String::with_capacity(value.len());
+    let mut rest = value;
+
+    while let Some(pos) = rest.find('%') {
+        out.push_str(&rest[..pos]);
+        rest = &rest[pos..];
+        if let Some(after) = rest.strip_prefix("%%") {
+            out.push('%');
+            rest = after;
+            continue;
+        }
+        let Some(close) = rest.strip_prefix("%(").and_then(|r| r.find(')')) else {
+            return Err(format!("lone `%` in `{}`, write `%%` for a literal one", value));
+        };
+        let name = &rest[2..close + 2];
+        let after = &rest[close + 3..];
+        let spec_len = after.find(|c: char| !c.is_ascii_digit() && c != '-').unwrap_or(after.len());
+        let (spec, conversion) = (&after[..spec_len], after[spec_len..].chars().next());
+        if !matches!(conversion, Some('s') | Some('d')) {
+            return Err(format!("`%({})` must end with s or d in `{}`", name, value));
+        }
+        rest = &after[spec_len + 1..];
+
+        let pad = |base: String| match spec.strip_prefix('0') {
+            Some(width) if !width.is_empty() => format!("{{{}:0{}}}", base, width),
+            _ => format!("{{{}}}", base),
+        };
+        let expanded = match name {
+            "program_name" => vars.program.to_string(),
+            "process_num" if vars.numprocs_start > 0 => pad(format!("instance+{}", vars.numprocs_start)),
+            "process_num" => pad("instance".to_string()),
+            "group_name" => vars.group.to_string(),
+            "numprocs" => vars.numprocs.to_string(),
+            "here" => vars.here.clone(),
+            "host_node_name" => std::fs::read_to_string("/proc/sys/kernel/hostname").unwrap_or_default().trim().to_string(),
+            env if env.starts_with("ENV_") => format!("${{{}}}", &env[4..]),
+            other => return Err(format!("unknown expansion `%({})`", other)),
+        };
+        out.push_str(&expanded);
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+
+
+
+
+
+/*
+    @@@
+    @split_command();
+    . Splits a supervisord 'command' into words like a POSIX shell would, without running one.
+    . Single quotes are literal, double quotes and backslashes escape whitespace.
+*/
+fn split_command(command: &str) -> Result<Vec<String>, String> {
+    let mut words = Vec::new();
+    let mut word: Option<String> = None;
+    let mut chars = command.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => words.extend(word.take()),
+            '\'' => {
+                let w = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => w.push(c),
+                        None => return Err(format!("unterminated quote in `{}`", command)),
+                    }
+                }
+            }
+            '"' => {
+                let w = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => w.extend(chars.next()),
+                        Some(c) => w.push(c),
+                        None => return Err(format!("unterminated quote in `{}`", command)),
+                    }
+                }
+            }
+            '\\' => word.get_or_insert_with(String::new).extend(chars.next()),
+            c => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    words.extend(word);
+    Ok(words)
+}
+
+// supervisord 'environment': KEY="value",KEY2=value2 (commas inside quotes are kept)
+fn parse_environment(value: &str) -> Result<Mapping, String> {
+    let mut env = Mapping::new();
+    let (mut items, mut item, mut quote) = (Vec::new(), String::new(), None);
+    for c in value.chars() {
+        match (c, quote) {
+            ('"' | '\'', None) => quote = Some(c),
+            (c, Some(q)) if c == q => quote = None,
+            (',', None) => items.push(std::mem::take(&mut item)),
+            (c, _) => item.push(c),
+        }
+    }
+    items.push(item);
+
+    for item in items.iter().map(|i| i.trim()).filter(|i| !i.is_empty()) {
+        let (key, val) = item.split_once('=').ok_or(format!("expected KEY=value, found `{}`", item))?;
+        env.insert(key.trim().into(), val.trim().into());
+    }
+    Ok(env)
+}
+
+// Numbers and booleans become typed values, so validation sees them like in YAML
+fn scalar(key: &str, value: String) -> Value {
+    match key {
+        "autostart" => match value.to_lowercase().as_str() {
+            "true" => Value::Bool(true),
+            "false" => Value::Bool(false),
+            _ => Value::String(value),
+        },
+        "numprocs" | "starttime" | "startretries" | "stoptime" | "buffer_size" => {
+            value.parse::<u64>().map(Value::from).unwrap_or(Value::String(value))
+        }
+        "events" => Value::Sequence(value.split(',').map(|e| e.trim().into()).collect()),
+        _ => Value::String(value),
+    }
+}
+
+
+
+
+
+
+/*
+    @@@
+    @convert_program();
+    . Maps a [program:x] or [eventlistener:x] section onto ProgramConfig keys, after %(name)s expansion.
+    . 'command' is split into cmd and args, 'redirect_stderr' sends stderr to the stdout file, AUTO/NONE log files are dropped.
+    . Options without an equivalent are skipped and noted, unknown ones are problems.
+    . 'user' is a problem too: skipping it would run the program as the supervisor's user, often root.
+*/
+fn convert_program(section: &Section, vars: &Vars, notes: &mut Vec<String>, problems: &mut Vec<Problem>) -> Mapping {
+    let mut program = Mapping::new();
+    let mut redirect_stderr = false;
+
+    for (key, raw, line) in &section.entries {
+        let mut problem = |message: String| {
+            problems.push(Problem {
+                file: None,
+                path: format!("programs.{}", vars.program),
+                location: Some((*line, 1)),
+                message,
+            });
+        };
+        let value = match expand(raw, vars) {
+            Ok(value) => value,
+            Err(e) => {
+                problem(e);
+                continue;
+            }
+        };
+
+        match key.as_str() {
+            "command" => match split_command(&value) {
+                Ok(words) if !words.is_empty() => {
+                    program.insert("cmd".into(), words[0].clone().into());
+                    program.insert("args".into(), Value::Sequence(words[1..].iter().map(|w| w.as_str().into()).collect()));
+                }
+                Ok(_) => problem("`command` is empty".to_string()),
+                Err(e) => problem(e),
+            },
+            "autorestart" => {
+                let policy = match value.to_lowercase() {
+                    v if v == "true" => "always".to_string(),
+                    v if v == "false" => "never".to_string(),
+                    other => other,
+                };
+                program.insert("autorestart".into(), policy.into());
+            }
+            "exitcodes" => {
+                let codes: Result<Vec<Value>, _> = value.split(',').map(|c| c.trim().parse::<u32>().map(Value::from)).collect();
+                match codes {
+                    Ok(codes) => { program.insert("exitcodes".into(), Value::Sequence(codes)); }
+                    Err(_) => problem(format!("`exitcodes` must be a comma-separated list of numbers, found `{}`", value)),
+                }
+            }
+            "environment" => match parse_environment(&value) {
+                Ok(env) => { program.insert("env".into(), Value::Mapping(env)); }
+                Err(e) => problem(e),
+            },
+            "redirect_stderr" => redirect_stderr = value.eq_ignore_ascii_case("true"),
+            "stdout_logfile" | "stderr_logfile" if matches!(value.to_uppercase().as_str(), "AUTO" | "NONE") => {
+                notes.push(format!("[{}] {} = {} : output is discarded", section.name, key, value));
+            }
+            "numprocs_start" => {}
+            "user" => problem(format!(
+                "`user = {}` is not supported, the program would run as the supervisor's own user; remove it and run the supervisor as that user",
+                value
+            )),
+            ignored if IGNORED.contains(&ignored) => notes.push(format!("[{}] {} is not supported, skipped", section.name, key)),
+            other => match RENAMED.iter().find(|(ini, _)| *ini == other) {
+                Some((_, yaml)) => { program.insert((*yaml).into(), scalar(yaml, value)); }
+                None => problem(format!("unknown supervisord option `{}`", other)),
+            },
+        }
+    }
+
+    if redirect_stderr {
+        if let Some(stdout) = program.get("stdout").cloned() {
+            program.insert("stderr".into(), stdout);
+        }
+    }
+    program
+}
+
+
+
+
+
+
+/*
+    @@@
+    @to_value();
+    . Converts a supervisord INI file into the layout of the YAML config: [program:x] and [eventlistener:x] become programs.
+    . [include] files become 'include', [supervisord] pidfile becomes 'pidfile' and [unix_http_server] file 'control_socket'.
+    . [group:x] only names the group for %(group_name)s, its programs are supervised on their own.
+    . Returns the value with notes on what was skipped, for `convert`.
+*/
+pub fn to_value(path: &str, text: &str) -> Result<(Value, Vec<String>), ConfigError> {
+    let sections = sections(text)?;
+    let here = Path::new(path).parent()
+        .and_then(|dir| std::fs::canonicalize(if dir.as_os_str().is_empty() { Path::new(".") } else { dir }).ok())
+        .map(|dir| dir.to_string_lossy().into_owned())
+        .unwrap_or_default();
+
+    let mut groups: HashMap<String, String> = HashMap::new();
+    for section in &sections {
+        if let Some(group) = section.name.strip_prefix("group:") {
+            for (_, value, _) in section.entries.iter().filter(|(key, _, _)| key == "programs") {
+                for program in value.split(',') {
+                    groups.insert(program.trim().to_string(), group.to_string());
+                }
+            }
+        }
+    }
+
+    let (mut root, mut programs) = (Mapping::new(), Mapping::new());
+    let (mut notes, mut problems) = (Vec::new(), Vec::new());
+    let get = |section: &Section, key: &str| {
+        section.entries.iter().find(|(k, _, _)| k == key).map(|(_, v, _)| v.clone())
+    };
+
+    for section in &sections {
+        let (kind, name) = section.name.split_once(':').unwrap_or((section.name.as_str(), ""));
+        match kind {
+            "program" | "eventlistener" => {
+                let vars = Vars {
+                    program: name,
+                    group: groups.get(name).map(String::as_str).unwrap_or(name),
+                    here: here.clone(),
+                    numprocs: get(section, "numprocs").and_then(|n| n.parse().ok()).unwrap_or(1),
+                    numprocs_start: get(section, "numprocs_start").and_then(|n| n.parse().ok()).unwrap_or(0),
+                };
+                if kind == "eventlistener" && get(section, "events").is_none() {
+                    problems.push(Problem {
+                        file: None,
+                        path: format!("programs.{}", name),
+                        location: Some((section.line, 1)),
+                        message: "an eventlistener needs `events`".to_string(),
+                    });
+                }
+                let program = convert_program(section, &vars, &mut notes, &mut problems);
+                programs.insert(name.into(), Value::Mapping(program));
+            }
+            "include" => {
+                let files = get(section, "files").unwrap_or_default();
+                root.insert("include".into(), Value::Sequence(files.split_whitespace().map(Value::from).collect()));
+            }
+            "supervisord" | "unix_http_server" => {
+                for (key, value, _) in &section.entries {
+                    match (kind, key.as_str()) {
+                        ("supervisord", "pidfile") => { root.insert("pidfile".into(), value.as_str().into()); }
+                        ("unix_http_server", "file") => { root.insert("control_socket".into(), value.as_str().into()); }
+                        _ => notes.push(format!("[{}] {} is not supported, skipped", section.name, key)),
+                    }
+                }
+            }
+            "group" => notes.push(format!("[{}] programs are supervised on their own", section.name)),
+            "inet_http_server" | "supervisorctl" | "rpcinterface" | "ctlplugin" => {
+                notes.push(format!("[{}] is not supported, skipped", section.name));
+            }
+            _ => problems.push(Problem {
+                file: None,
+                path: String::new(),
+                location: Some((section.line, 1)),
+                message: format!("unsupported section [{}]", section.name),
+            }),
+        }
+    }
+
+    if !problems.is_empty() {
+        return Err(ConfigError(problems));
+    }
+    if !programs.is_empty() || !root.contains_key("include") {
+        root.insert("programs".into(), Value::Mapping(programs));
+    }
+    Ok((Value::Mapping(root), notes))
+}
+
+
+
+
+
+
+/*
+    @@@
+    @locate();
+    . Finds the line of a key path (e.g. ["programs", "web", "stdout"]) in INI text: the program's section, then its supervisord option.
+*/
+pub fn locate(text: &str, path: &[&str]) -> Option<(usize, usize)> {
+    let lines: Vec<&str> = text.lines().collect();
+    let header = |names: &[String]| lines.iter().position(|l| names.iter().any(|n| l.trim() == n));
+
+    let (section, key) = match path {
+        ["include"] => return header(&["[include]".to_string()]).map(|idx| (idx + 1, 1)),
+        ["pidfile"] => (header(&["[supervisord]".to_string()])?, "pidfile"),
+        ["control_socket"] => (header(&["[unix_http_server]".to_string()])?, "file"),
+        ["programs", name, rest @ ..] => {
+            let idx = header(&[format!("[program:{}]", name), format!("[eventlistener:{}]", name)])?;
+            let key = match rest.first() {
+                None => return Some((idx + 1, 1)),
+                Some(&"cmd") | Some(&"args") => "command",
+                Some(&"env") => "environment",
+                Some(key) => RENAMED.iter().find(|(_, yaml)| yaml == key).map(|(ini, _)| *ini).unwrap_or(key),
+            };
+            (idx, key)
+        }
+        _ => return None,
+    };
+
+    lines.iter().enumerate().skip(section + 1)
+        .take_while(|(_, l)| !l.trim_start().starts_with('['))
+        .find(|(_, l)| l.split(['=', ':']).next().is_some_and(|k| k.trim() == key))
+        .map(|(idx, l)| (idx + 1, l.len() - l.trim_start().len() + 1))
+}
+
+
+
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn program(value: &Value, name: &str) -> Mapping {
+        value["programs"][name].as_mapping().cloned().unwrap()
+    }
+
+    #[test]
+    fn converts_a_program_section() {
+        let text = "\
+[program:web]
+command = /usr/bin/python3 -m http.server 'a b' \"c \\\" d\" e\\ f
+numprocs = 2
+startsecs = 5
+autorestart = true
+environment = A=\"1,2\",B=plain
+stdout_logfile = /var/log/%(program_name)s-%(process_num)02d.log
+redirect_stderr = true
+priority = 10
+";
+        let (value, notes) = to_value("/nonexistent/supervisord.conf", text).unwrap();
+        let web = program(&value, "web");
+        assert_eq!(web["cmd"], Value::from("/usr/bin/python3"));
+        let args: Vec<Value> = ["-m", "http.server", "a b", "c \" d", "e f"].into_iter().map(Value::from).collect();
+        assert_eq!(web["args"], Value::Sequence(args));
+        assert_eq!(web["numprocs"], Value::from(2u64));
+        assert_eq!(web["starttime"], Value::from(5u64));
+        assert_eq!(web["autorestart"], Value::from("always"));
+        assert_eq!(web["env"]["A"], Value::from("1,2"));
+        assert_eq!(web["env"]["B"], Value::from("plain"));
+        assert_eq!(web["stdout"], Value::from("/var/log/web-{instance:02}.log"));
+        assert_eq!(web["stderr"], web["stdout"]);
+        assert_eq!(notes, vec!["[program:web] priority is not supported, skipped".to_string()]);
+    }
+
+    #[test]
+    fn process_num_follows_numprocs_start() {
+        let text = "[program:w]\ncommand = w --port %(process_num)d --id %(process_num)03d\nnumprocs_start = 8000\n";
+        let (value, _) = to_value("w.conf", text).unwrap();
+        let args: Vec<Value> = ["--port", "{instance+8000}", "--id", "{instance+8000:03}"].into_iter().map(Value::from).collect();
+        assert_eq!(program(&value, "w")["args"], Value::Sequence(args));
+    }
+
+    #[test]
+    fn expansions() {
+        let vars = Vars { program: "web", group: "front", here: "/etc/sv".to_string(), numprocs: 3, numprocs_start: 0 };
+        assert_eq!(
+            expand("%(group_name)s/%(program_name)s x%(numprocs)d %(here)s 100%%", &vars).unwrap(),
+            "front/web x3 /etc/sv 100%",
+        );
+        assert_eq!(expand("%(ENV_HOME)s/%(process_num)s", &vars).unwrap(), "${HOME}/{instance}");
+        assert!(expand("50% off", &vars).is_err());
+        assert!(expand("%(program_name)x", &vars).is_err());
+        assert!(expand("%(nope)s", &vars).is_err());
+    }
+
+    #[test]
+    fn user_and_unknown_options_are_problems() {
+        let err = to_value("x.conf", "[program:a]\ncommand = a\nuser = www-data\nbogus = 1\n").unwrap_err();
+        let lines: Vec<Option<(usize, usize)>> = err.0.iter().map(|problem| problem.location).collect();
+        assert_eq!(lines, vec![Some((3, 1)), Some((4, 1))]);
+        assert!(err.0[0].message.contains("user = www-data"));
+    }
+
+    #[test]
+    fn split_command_words() {
+        assert_eq!(split_command("  a  'b c'd \"e\\\"f\" g\\ h ").unwrap(), ["a", "b cd", "e\"f", "g h"]);
+        assert_eq!(split_command("a ''").unwrap(), ["a", ""]);
+        assert!(split_command("a 'b").is_err());
+        assert!(split_command("a \"b").is_err());
+    }
+
+    #[test]
+    fn parse_environment_pairs() {
+        let env = parse_environment("A=1, B='x,y' ,C=\"=z\",").unwrap();
+        let pairs: Vec<(&str, &str)> = env.iter().map(|(k, v)| (k.as_str().unwrap(), v.as_str().unwrap())).collect();
+        assert_eq!(pairs, [("A", "1"), ("B", "x,y"), ("C", "=z")]);
+        assert!(parse_environment("A=1,B").is_err());
+    }
+
+    #[test]
+    fn locate_options() {
+        let text = "[supervisord]\npidfile = /run/s.pid\n\n[eventlistener:mail]\nevents = TICK_60\n  stdout_logfile: mail.log\n";
+        assert_eq!(locate(text, &["pidfile"]), Some((2, 1)));
+        assert_eq!(locate(text, &["programs", "mail"]), Some((4, 1)));
+        assert_eq!(locate(text, &["programs", "mail", "stdout"]), Some((6, 3)));
+        assert_eq!(locate(text, &["programs", "mail", "umask"]), None);
+    }
+}
@@ -0,0 +1,102 @@
+use crate::control::stop_and_cleanup_pid;
+use crate::parse::ProgramConfig;
+use crate::runtime::SupervisorState;
+use nix::libc;
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+use tokio::sync::Notify;
+use tracing::{info, warn};
+
+
+// Set once at startup by --init
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+// Exit code requested by a dying critical program
+static EXIT_CODE: AtomicI32 = AtomicI32::new(0);
+static EXIT: OnceLock<Notify> = OnceLock::new();
+
+fn exit_notify() -> &'static Notify {
+    EXIT.get_or_init(Notify::new)
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+
+
+
+
+
+/*
+    @@@
+    @enable();
+    . Switches the supervisor to init mode.
+    . When it isn't PID 1, registers it as a child subreaper (PR_SET_CHILD_SUBREAPER), so orphaned grandchildren are reparented to it instead of the real init.
+*/
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+
+    if std::process::id() == 1 {
+        info!("Running as PID 1");
+        return;
+    }
+    let ret = unsafe { libc::prctl(libc::PR_SET_CHILD_SUBREAPER, 1, 0, 0, 0) };
+    if ret == 0 {
+        info!("Registered as child subreaper");
+    } else {
+        warn!("PR_SET_CHILD_SUBREAPER failed: {}", std::io::Error::last_os_error());
+    }
+}
+
+
+
+
+
+
+/*
+    @@@
+    @request_exit();
+    . Asks the supervisor to shut down and exit with 'code', used when a critical program dies.
+*/
+pub fn request_exit(code: i32) {
+    EXIT_CODE.store(code, Ordering::Relaxed);
+    exit_notify().notify_one();
+}
+
+pub async fn exit_requested() -> i32 {
+    exit_notify().notified().await;
+    EXIT_CODE.load(Ordering::Relaxed)
+}
+
+
+
+
+
+
+/*
+    @@@
+    @shutdown_all();
+    . Sends 'signal' (e.g. TERM or INT, as received by the supervisor) to every instance instead of its stopsignal.
+    . Instances are stopped concurrently, each one is force-killed after its own 'stoptime'.
+*/
+pub async fn shutdown_all(state: &SupervisorState, signal: &str) {
+    let targets: Vec<(String, usize, nix::unistd::Pid, ProgramConfig)> = {
+        let mut map = state.write().await;
+        map.iter_mut()
+            .flat_map(|(name, job)| {
+                let cfg = ProgramConfig { stopsignal: signal.to_string(), ..job.config.clone() };
+                std::mem::take(&mut job.children).into_iter().map(move |(num, pid)| (name.clone(), num, pid, cfg.clone())).collect::<Vec<_>>()
+            })
+            .collect()
+    };
+
+    info!(signal, instances = targets.len(), "Stopping all programs");
+    let handles: Vec<_> = targets
+        .into_iter()
+        .map(|(name, num, pid, cfg)| tokio::task::spawn_blocking(move || stop_and_cleanup_pid(&name, num, pid, &cfg)))
+        .collect();
+    for handle in handles {
+        let _ = handle.await;
+    }
+}
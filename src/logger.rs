@@ -1,28 +1,74 @@
 use tracing_subscriber::fmt::{SubscriberBuilder};
 use tracing_appender::rolling::{RollingFileAppender, Rotation};
 use tracing_appender::non_blocking::WorkerGuard;
+use crate::secrets::Redacting;
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+
+// Bumped by reopen() (SIGUSR2), every log writer reopens its file when it sees a new value
+static GENERATION: AtomicUsize = AtomicUsize::new(0);
+
+pub fn reopen() {
+    GENERATION.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn generation() -> usize {
+    GENERATION.load(Ordering::Relaxed)
+}
+
+
+
+// Supervisor log file, recreated after reopen() so an external logrotate can move it away
+struct ReopeningAppender {
+    appender: RollingFileAppender,
+    generation: usize,
+}
+
+impl Write for ReopeningAppender {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.generation != generation() {
+            self.generation = generation();
+            match RollingFileAppender::builder().rotation(Rotation::DAILY).filename_prefix("supervisor.log").build("logs") {
+                Ok(appender) => self.appender = appender,
+                Err(e) => eprintln!("Cannot reopen the supervisor log: {}", e),
+            }
+        }
+        self.appender.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.appender.flush()
+    }
+}
 
 
 
 /*
     @@@
     @logs_tracing();
-    . Creates a daily-rotating log file (logs/supervisor.log) and wraps it in a non-blocking writer.
-    . Configures a tracing subscriber to log INFO-level events (with timestamps, thread IDs, and targets) to that writer.
+    . Creates a daily-rotating log file (logs/supervisor.log) --or uses stdout in init mode-- and wraps it in a non-blocking writer.
+    . The log file is reopened on the next line after reopen().
+    . Secret values are redacted before anything reaches the log.
+    . Configures a tracing subscriber to log events up to 'level' (with timestamps, thread IDs, and targets) to that writer.
     . keeps the appender alive by returning the guard.
 */
-pub fn logs_tracing() -> WorkerGuard {
-    let file_appender = RollingFileAppender::new(Rotation::DAILY, "logs", "supervisor.log");
-    let (non_blocking, guard) = tracing_appender::non_blocking(file_appender);
+pub fn logs_tracing(level: tracing::Level, to_stdout: bool) -> WorkerGuard {
+    let (non_blocking, guard) = if to_stdout {
+        tracing_appender::non_blocking(Redacting(std::io::stdout()))
+    } else {
+        let file_appender = RollingFileAppender::new(Rotation::DAILY, "logs", "supervisor.log");
+        tracing_appender::non_blocking(Redacting(ReopeningAppender { appender: file_appender, generation: generation() }))
+    };
 
     let subscriber = SubscriberBuilder::default()
         .with_ansi(false)
         .with_target(true)
         .with_level(true)
         .with_writer(non_blocking)
-        .with_max_level(tracing::Level::INFO)
+        .with_max_level(level)
         .finish();
 
     tracing::subscriber::set_global_default(subscriber).expect("Failed to set global subscriber");
     guard
-}
\ No newline at end of file
+}
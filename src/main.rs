@@ -3,12 +3,37 @@ mod runtime;
 mod logger;
 mod shell;
 mod control;
+mod events;
+mod hooks;
+mod metrics;
+mod procfs;
+mod stats;
+mod watchdog;
+mod cli;
+mod socket;
+mod daemon;
+mod init;
+mod output;
+mod validate;
+mod expand;
+mod ini;
+mod environ;
+mod secrets;
+mod watcher;
+mod statefile;
+mod reexec;
+mod activation;
 
-use parse::{parser};
+use parse::{parser, Config};
 use runtime::{apply_config, SupervisorState, reap_children};
 use logger::{logs_tracing};
 use shell::run_shell;
-use control::{start_program, stop_program};
+use control::{start_program, stop_program, restart_program, status_report, reload_config, Context};
+use cli::{Cli, Command};
+use clap::Parser;
+use futures::StreamExt;
+use signal_hook::consts::signal::{SIGHUP, SIGINT, SIGTERM, SIGUSR2};
+use signal_hook_tokio::Signals;
 use std::collections::HashMap;
 use std::sync::Arc;
 use tokio::sync::RwLock;
@@ -19,66 +44,213 @@ use tokio::runtime::Builder;
 /*
     @@@
     @async_main();
-    . Parses the config file and initializes a shared, thread‐safe map guarded by an RwLock.
-    . Sets up tracing/logging and applies the initial config (spawning all autostart processes).
-    . Returns an async move based on the closures --status, reload, start, stop and exit-- which performs the requested operation.
+    . Takes the config parsed from the file given on the command line and initializes a shared, thread‐safe map guarded by an RwLock.
+    . Sets up tracing/logging, applies the initial config (spawning all autostart processes) and opens the control socket.
+    . Logs a warning for each option of a supervisord INI file that was skipped.
+    . With --adopt, first takes back the still-running instances recorded in the state file, which is then kept up to date.
+    . After a self-upgrade, always takes them back, with the output pipes handed over by the previous process.
+    . Watches the config file for changes when 'watch_config' is set, and the files of programs with 'watch'.
+    . Returns an async move based on the closures --status, reload, start, stop, restart, stats and exit-- which performs the requested operation.
+    . SIGHUP reloads the config and SIGUSR2 reopens the log files (see handle_signals()).
+    . Without the shell (--no-shell, --daemon or --init), waits for SIGINT/SIGTERM instead.
+    . In init mode, forwards that signal to every program --or stops them when a critical one died-- and returns the exit code.
 */
-async fn async_main() -> Result<(), Box<dyn std::error::Error>> {
-    let cfg = Arc::new(parser("config/config.yml")?);
+async fn async_main(cli: Cli, cfg: Config) -> Result<i32, Box<dyn std::error::Error>> {
     let state: SupervisorState = Arc::new(RwLock::new(HashMap::new()));
-    
-    let _guard = logs_tracing();
-    tracing::info!("Supervisor started!");
-    
+
+    let _guard = logs_tracing(cli.log_level, cli.init);
+    tracing::info!(config = %cli.config, "Supervisor started!");
+    for note in &cfg.notes {
+        tracing::warn!("{}", note);
+    }
+    if cli.init {
+        init::enable();
+    }
+
+    let state_file = cfg.state_file.clone();
+    if reexec::resumed() {
+        let adopted = statefile::adopt(&cfg, &state).await;
+        tracing::info!(instances = adopted.len(), "Resumed after self-upgrade");
+        reexec::resume_pipes();
+        activation::resume();
+        tokio::spawn(statefile::watch_adopted(state.clone(), adopted));
+    } else if cli.adopt {
+        let adopted = statefile::adopt(&cfg, &state).await;
+        tracing::info!(state_file = %state_file, instances = adopted.len(), "Adopted instances of the previous run");
+        tokio::spawn(statefile::watch_adopted(state.clone(), adopted));
+    } else if statefile::running(&state_file) > 0 {
+        tracing::warn!(state_file = %state_file, "Instances of a previous run are still running; start with --adopt to take them back");
+    }
+
     apply_config(&cfg, state.clone()).await;
+    tokio::spawn(statefile::persist_state(state.clone(), state_file.clone()));
     tokio::spawn(reap_children(state.clone()));
+    tokio::spawn(events::run_ticker());
+    tokio::spawn(stats::run_sampler(state.clone()));
+    tokio::spawn(watchdog::run_watchdog(state.clone()));
+    if let Some(addr) = cfg.metrics_addr.clone() {
+        tokio::spawn(metrics::serve_metrics(addr, state.clone()));
+    }
+
+    let ctx = Context {
+        config_path: cli.config.clone(),
+        config: Arc::new(RwLock::new(cfg)),
+        state: state.clone(),
+    };
+    let control_socket = ctx.config.read().await.control_socket.clone();
+    tokio::spawn(socket::serve_control(control_socket.clone(), ctx.clone()));
+    if ctx.config.read().await.watch_config {
+        tokio::spawn(watcher::watch_config(ctx.clone()));
+    }
+    tokio::spawn(watcher::watch_programs(ctx.clone()));
+    tokio::spawn(handle_signals(ctx.clone(), Signals::new([SIGHUP, SIGUSR2])?));
+
+    if cli.no_shell || cli.daemon || cli.init {
+        let mut signals = Signals::new([SIGINT, SIGTERM])?;
+        let code = tokio::select! {
+            sig = signals.next() => {
+                let sig = sig.unwrap_or(SIGTERM);
+                tracing::info!(signal = sig, "Supervisor exited!");
+                if cli.init {
+                    init::shutdown_all(&state, if sig == SIGINT { "INT" } else { "TERM" }).await;
+                }
+                0
+            }
+            code = init::exit_requested() => {
+                init::shutdown_all(&state, "TERM").await;
+                tracing::info!(exit_code = code, "Supervisor exited!");
+                code
+            }
+        };
+        statefile::save(&*state.read().await, &state_file);
+        let _ = std::fs::remove_file(&control_socket);
+        return Ok(code);
+    }
 
-    let status_state = state.clone();
-    let reload_state = state.clone();
-    let start_state  = state.clone();
+    let status_ctx   = ctx.clone();
+    let reload_ctx   = ctx.clone();
+    let start_ctx    = ctx.clone();
     let stop_state   = state.clone();
-    let cfg_for_start = cfg.clone();
-    
+    let restart_state = state.clone();
+    let stats_state  = state.clone();
+
     run_shell(
-        move || {
-            let state = status_state.clone();
+        move |args: &str| {
+            let ctx = status_ctx.clone();
+            let verbose = args == "--verbose";
             async move {
-                let map = state.read().await;
-                for (name, job) in map.iter() {
-                    println!("{} : {} instance(s)", name, job.children.len());
-                }
+                let origins = ctx.config.read().await.origins.clone();
+                let map = ctx.state.read().await;
+                print!("{}", status_report(&map, verbose.then_some(&origins)));
             }
         },
         move || {
-            let state = reload_state.clone();
+            let ctx = reload_ctx.clone();
             async move {
-                if let Ok(new_cfg) = parser("config/config.yml") {
-                    apply_config(&new_cfg, state).await;
-                    println!("Configuration reloaded");
-                } else {
-                    println!("Failed to reload config");
-                }
+                println!("{}", reload_config(&ctx).await);
             }
         },
         move |prog: &str| {
-            let state = start_state.clone();
-            let cfg = cfg_for_start.clone();
+            let ctx = start_ctx.clone();
             let prog = prog.to_string();
             async move {
-                start_program(&prog, &cfg.programs, state).await;
+                let programs = ctx.config.read().await.programs.clone();
+                println!("{}", start_program(&prog, &programs, ctx.state.clone()).await);
             }
         },
         move |prog: &str| {
             let state = stop_state.clone();
             let prog = prog.to_string();
             async move {
-                stop_program(&prog, state).await;
+                println!("{}", stop_program(&prog, state).await);
+            }
+        },
+        move |args: &str| {
+            let state = restart_state.clone();
+            let rolling = args.split_whitespace().any(|a| a == "--rolling");
+            let prog = args.split_whitespace().find(|a| !a.starts_with("--")).unwrap_or_default().to_string();
+            async move {
+                println!("{}", restart_program(&prog, state, rolling).await);
+            }
+        },
+        move |args: &str| {
+            let state = stats_state.clone();
+            let tree = args.split_whitespace().any(|a| a == "--tree");
+            let prog = args.split_whitespace().find(|a| !a.starts_with("--")).map(String::from);
+            async move {
+                let map = state.read().await;
+                print!("{}", stats::render_stats(&map, prog.as_deref(), tree));
             }
         },
     )
     .await
     .unwrap();
 
+    statefile::save(&*state.read().await, &state_file);
+    let _ = std::fs::remove_file(&control_socket);
+    Ok(0)
+}
+
+
+
+
+/*
+    @@@
+    @handle_signals();
+    . SIGHUP runs the same reload as the shell's `reload`: the config is re-read, validated and applied, or kept on errors.
+    . SIGUSR2 reopens the supervisor log and the stdout/stderr log files of programs with 'reopen_logs', e.g. after logrotate moved them.
+*/
+async fn handle_signals(ctx: Context, mut signals: Signals) {
+    while let Some(sig) = signals.next().await {
+        match sig {
+            SIGHUP => {
+                tracing::info!("SIGHUP received; reloading config");
+                reload_config(&ctx).await;
+            }
+            SIGUSR2 => {
+                logger::reopen();
+                tracing::info!("SIGUSR2 received; log files reopened");
+            }
+            _ => {}
+        }
+    }
+}
+
+
+
+
+/*
+    @@@
+    @ctl_main();
+    . One-shot client: reads only the control socket path from the config file and sends the command to the running supervisor.
+    . Prints the reply, or fails if no supervisor is listening.
+*/
+fn ctl_main(config_path: &str, args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let control_socket = parse::control_socket(config_path)?;
+    let reply = socket::send_command(&control_socket, &args.join(" "))
+        .map_err(|e| format!("cannot reach supervisor on {}: {}", control_socket, e))?;
+    print!("{}", reply);
+    Ok(())
+}
+
+
+
+
+/*
+    @@@
+    @convert_main();
+    . Converts a supervisord INI file and prints the equivalent YAML config, what couldn't be converted is listed in comments.
+    . The output isn't validated, run it through --check once saved.
+*/
+fn convert_main(path: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let text = std::fs::read_to_string(path)?;
+    let (value, notes) = ini::to_value(path, &text)?;
+
+    println!("# Converted from {}", path);
+    for note in notes {
+        println!("# {}", note);
+    }
+    print!("{}", serde_yaml::to_string(&value)?);
     Ok(())
 }
 
@@ -88,18 +260,79 @@ async fn async_main() -> Result<(), Box<dyn std::error::Error>> {
 /*
     @@@
     @main();
+    . Parses the command line, `ctl`, `convert` and `--check` are handled right away without starting the supervisor.
+    . Locks the pidfile --refusing to run next to another supervisor on the same config-- and detaches with --daemon.
     . Builds a multi-threaded runtime with 4 workers and wraps it in a LocalSet to allow non-Send tasks.
     . Uses 4 OS threads for driving async tasks --interactive shell, child monitoring, spawning/killing processes, tracing and other tasks-- each for one.
     . Run async_main as the root future on that runtime, catching any top-level errors before exiting.
 */
 fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let cli = Cli::parse();
+    reexec::take_handoff();
+    if let Some(format) = cli.format {
+        parse::Format::set_main(format);
+    }
+
+    if let Some(Command::Ctl { args }) = &cli.command {
+        if let Err(e) = ctl_main(&cli.config, args) {
+            eprintln!("supervisor ctl: {}", e);
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+    if let Some(Command::Convert { file }) = &cli.command {
+        if let Err(e) = convert_main(file) {
+            eprintln!("supervisor convert: {}: {}", file, e);
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    let cfg = match parser(&cli.config) {
+        Ok(cfg) => cfg,
+        Err(e) => {
+            eprintln!("{}: {}", cli.config, e);
+            std::process::exit(1);
+        }
+    };
+    if cli.check {
+        for note in &cfg.notes {
+            eprintln!("warning: {}", note);
+        }
+        println!("{}: OK ({} program(s))", cli.config, cfg.programs.len());
+        return Ok(());
+    }
+
+    let pidfile_path = cfg.pidfile.clone();
+    let mut pidfile = match daemon::lock_pidfile(&pidfile_path) {
+        Ok(pidfile) => pidfile,
+        Err(e) => {
+            eprintln!("Supervisor error: {}", e);
+            std::process::exit(1);
+        }
+    };
+    // A self-upgraded supervisor is already detached, and must keep its pid to keep its children
+    if cli.daemon && !reexec::resumed() {
+        daemon::daemonize()?;
+    }
+    daemon::write_pid(&mut pidfile)?;
+
     let multi_thread_runtime =  Builder::new_multi_thread().worker_threads(4).enable_all().build()?;
     let local = LocalSet::new();
-    local.block_on(&multi_thread_runtime, async {
-        if let Err(e) = async_main().await {
-            eprintln!("Supervisor error: {}", e);
+    let code = local.block_on(&multi_thread_runtime, async {
+        match async_main(cli, cfg).await {
+            Ok(code) => code,
+            Err(e) => {
+                tracing::error!("Supervisor error: {}", e);
+                eprintln!("Supervisor error: {}", e);
+                1
+            }
         }
     });
 
+    let _ = std::fs::remove_file(&pidfile_path);
+    if code != 0 {
+        std::process::exit(code);
+    }
     Ok(())
-}
\ No newline at end of file
+}
@@ -0,0 +1,208 @@
+use crate::procfs;
+use crate::reexec;
+use crate::runtime::{RuntimeJob, SupervisorState};
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::os::fd::AsRawFd;
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tracing::{info, warn};
+
+
+// Supervisor-wide counters, bumped by the runtime
+pub static SPAWNS: AtomicU64 = AtomicU64::new(0);
+pub static EXITS: AtomicU64 = AtomicU64::new(0);
+pub static RESTARTS: AtomicU64 = AtomicU64::new(0);
+
+static STARTED: OnceLock<Instant> = OnceLock::new();
+
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+
+
+
+
+
+/*
+    @@@
+    @render();
+    . Renders the supervisor state in Prometheus text exposition format (version 0.0.4).
+    . Program-level series come from RuntimeJob, per-instance CPU, RSS, threads and uptime are read from /proc/<pid>/stat.
+    . Instance series are labelled with the instance number rather than the pid, which is a value of its own (supervisor_instance_pid).
+*/
+pub fn render(map: &HashMap<String, RuntimeJob>) -> String {
+    let mut out = String::new();
+    let mut names: Vec<&String> = map.keys().collect();
+    names.sort();
+
+    header(&mut out, "supervisor_uptime_seconds", "gauge", "Seconds since the supervisor started.");
+    let uptime = STARTED.get_or_init(Instant::now).elapsed().as_secs_f64();
+    let _ = writeln!(out, "supervisor_uptime_seconds {:.3}", uptime);
+
+    for (name, counter, help) in [
+        ("supervisor_spawns_total", &SPAWNS, "Instances spawned by the supervisor."),
+        ("supervisor_exits_total", &EXITS, "Instance exits observed by the supervisor."),
+        ("supervisor_restarts_total", &RESTARTS, "Automatic restarts performed by the supervisor."),
+    ] {
+        header(&mut out, name, "counter", help);
+        let _ = writeln!(out, "{} {}", name, counter.load(Ordering::Relaxed));
+    }
+
+    header(&mut out, "supervisor_program_up", "gauge", "1 if every configured instance of the program is running.");
+    for name in &names {
+        let job = &map[*name];
+        let up = !job.children.is_empty() && job.children.len() >= job.config.numprocs;
+        let _ = writeln!(out, "supervisor_program_up{{program=\"{}\"}} {}", escape(name), up as u8);
+    }
+
+    header(&mut out, "supervisor_program_running_instances", "gauge", "Number of running instances.");
+    for name in &names {
+        let _ = writeln!(
+            out,
+            "supervisor_program_running_instances{{program=\"{}\"}} {}",
+            escape(name), map[*name].children.len()
+        );
+    }
+
+    header(&mut out, "supervisor_program_configured_instances", "gauge", "Configured numprocs.");
+    for name in &names {
+        let _ = writeln!(
+            out,
+            "supervisor_program_configured_instances{{program=\"{}\"}} {}",
+            escape(name), map[*name].config.numprocs
+        );
+    }
+
+    header(&mut out, "supervisor_program_restarts_total", "counter", "Automatic restarts of the program.");
+    for name in &names {
+        let _ = writeln!(
+            out,
+            "supervisor_program_restarts_total{{program=\"{}\"}} {}",
+            escape(name), map[*name].restarts
+        );
+    }
+
+    header(&mut out, "supervisor_program_last_exit_code", "gauge", "Exit code of the last instance that exited.");
+    for name in &names {
+        if let Some(code) = map[*name].last_exit {
+            let _ = writeln!(out, "supervisor_program_last_exit_code{{program=\"{}\"}} {}", escape(name), code);
+        }
+    }
+
+    let ticks = procfs::clock_ticks() as f64;
+    let page_size = procfs::page_size();
+    let mut up = String::new();
+    let mut pids = String::new();
+    let mut uptime = String::new();
+    let mut cpu = String::new();
+    let mut rss = String::new();
+    let mut threads = String::new();
+    for name in &names {
+        // Labelled by instance number, which a restart keeps, so restarts don't create new series
+        for (num, pid) in &map[*name].children {
+            let labels = format!("program=\"{}\",instance=\"{}\"", escape(name), num);
+            let stat = procfs::read_stat(*pid);
+            let _ = writeln!(up, "supervisor_instance_up{{{}}} {}", labels, stat.is_some() as u8);
+            let _ = writeln!(pids, "supervisor_instance_pid{{{}}} {}", labels, pid);
+            let Some(stat) = stat else { continue };
+
+            if let Some(secs) = procfs::uptime_secs(&stat) {
+                let _ = writeln!(uptime, "supervisor_instance_uptime_seconds{{{}}} {:.3}", labels, secs);
+            }
+            let _ = writeln!(
+                cpu,
+                "supervisor_instance_cpu_seconds_total{{{}}} {:.2}",
+                labels, (stat.utime + stat.stime) as f64 / ticks
+            );
+            let _ = writeln!(
+                rss,
+                "supervisor_instance_resident_memory_bytes{{{}}} {}",
+                labels, stat.rss_pages * page_size
+            );
+            let _ = writeln!(threads, "supervisor_instance_threads{{{}}} {}", labels, stat.num_threads);
+        }
+    }
+
+    header(&mut out, "supervisor_instance_up", "gauge", "1 if the instance process exists.");
+    out.push_str(&up);
+    header(&mut out, "supervisor_instance_pid", "gauge", "Process id of the instance.");
+    out.push_str(&pids);
+    header(&mut out, "supervisor_instance_uptime_seconds", "gauge", "Seconds since the instance started.");
+    out.push_str(&uptime);
+    header(&mut out, "supervisor_instance_cpu_seconds_total", "counter", "User and system CPU time of the instance.");
+    out.push_str(&cpu);
+    header(&mut out, "supervisor_instance_resident_memory_bytes", "gauge", "Resident set size of the instance.");
+    out.push_str(&rss);
+    header(&mut out, "supervisor_instance_threads", "gauge", "Number of threads of the instance.");
+    out.push_str(&threads);
+
+    out
+}
+
+
+
+
+
+
+/*
+    @@@
+    @serve_metrics();
+    . Binds a minimal HTTP/1.0 listener on 'addr' (or takes it back after a self-upgrade) and answers 'GET /metrics' with the rendered state.
+    . Any other path gets a 404, each connection is closed after one response.
+*/
+pub async fn serve_metrics(addr: String, state: SupervisorState) {
+    STARTED.get_or_init(Instant::now);
+
+    let listener = match reexec::inherited_listener("metrics") {
+        Some(fd) => {
+            let listener = std::net::TcpListener::from(fd);
+            listener.set_nonblocking(true).and_then(|()| TcpListener::from_std(listener))
+        }
+        None => TcpListener::bind(&addr).await,
+    };
+    let listener = match listener {
+        Ok(listener) => listener,
+        Err(e) => {
+            warn!("Failed to bind metrics listener on {}: {}", addr, e);
+            return;
+        }
+    };
+    reexec::register_listener("metrics", listener.as_raw_fd());
+    info!("Metrics available on http://{}/metrics", addr);
+
+    loop {
+        let Ok((mut socket, _)) = listener.accept().await else {
+            continue;
+        };
+        let state = state.clone();
+
+        tokio::spawn(async move {
+            let mut buf = [0u8; 1024];
+            let n = socket.read(&mut buf).await.unwrap_or(0);
+            let request = String::from_utf8_lossy(&buf[..n]);
+            let path = request.split_whitespace().nth(1).unwrap_or("");
+
+            let response = if request.starts_with("GET ") && (path == "/metrics" || path.starts_with("/metrics?")) {
+                let body = render(&*state.read().await);
+                format!(
+                    "HTTP/1.0 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\n\r\n{}",
+                    body.len(), body
+                )
+            } else {
+                "HTTP/1.0 404 Not Found\r\nContent-Length: 0\r\n\r\n".to_string()
+            };
+            let _ = socket.write_all(response.as_bytes()).await;
+            let _ = socket.shutdown().await;
+        });
+    }
+}
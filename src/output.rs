@@ -0,0 +1,143 @@
+use crate::logger;
+use crate::secrets;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{self, Write};
+use std::os::fd::{AsRawFd, OwnedFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use nix::unistd::Pid;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, BufReader};
+use tokio::net::unix::pipe;
+
+
+// Where the output read from a child's pipe goes
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum PipeTarget {
+    File(PathBuf),
+    Console { prefix: String, stderr: bool },
+}
+
+// A pipe being forwarded, what a self-upgrade hands over to the new process
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ForwardedPipe {
+    pub fd: RawFd,
+    // The instance writing to it
+    pub pid: i32,
+    pub target: PipeTarget,
+}
+
+static FORWARDED: Mutex<Vec<ForwardedPipe>> = Mutex::new(Vec::new());
+
+// Keeps a pipe in FORWARDED for as long as its forwarding task runs
+struct Registration(RawFd);
+
+impl Registration {
+    fn new(fd: RawFd, pid: Pid, target: PipeTarget) -> Self {
+        FORWARDED.lock().unwrap_or_else(|e| e.into_inner()).push(ForwardedPipe { fd, pid: pid.as_raw(), target });
+        Registration(fd)
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        FORWARDED.lock().unwrap_or_else(|e| e.into_inner()).retain(|pipe| pipe.fd != self.0);
+    }
+}
+
+pub fn forwarded() -> Vec<ForwardedPipe> {
+    FORWARDED.lock().unwrap_or_else(|e| e.into_inner()).clone()
+}
+
+// Blocks until everything 'pid' wrote to its log file pipes is in the files, or 'timeout' elapsed
+pub fn wait_drained(pid: Pid, timeout: Duration) {
+    let deadline = Instant::now() + timeout;
+    let pending = || FORWARDED.lock().unwrap_or_else(|e| e.into_inner())
+        .iter()
+        .any(|pipe| pipe.pid == pid.as_raw() && matches!(pipe.target, PipeTarget::File(_)));
+    while pending() && Instant::now() < deadline {
+        std::thread::sleep(Duration::from_millis(20));
+    }
+}
+
+
+
+
+
+
+/*
+    @@@
+    @forward_to_console();
+    . Reads a child's stdout (or stderr) pipe line by line until the child closes it.
+    . Writes each line to the supervisor's own stdout (or stderr), prefixed with '[program:pid]' and with secret values redacted.
+*/
+pub async fn forward_to_console(prefix: String, pid: Pid, fd: OwnedFd, is_stderr: bool) {
+    let _registration = Registration::new(fd.as_raw_fd(), pid, PipeTarget::Console { prefix: prefix.clone(), stderr: is_stderr });
+    let Ok(reader) = pipe::Receiver::from_owned_fd(fd) else {
+        return;
+    };
+    let mut lines = BufReader::new(reader).lines();
+
+    while let Ok(Some(line)) = lines.next_line().await {
+        let line = format!("[{}] {}\n", prefix, secrets::redact(&line));
+        if is_stderr {
+            let _ = std::io::stderr().lock().write_all(line.as_bytes());
+        } else {
+            let _ = std::io::stdout().lock().write_all(line.as_bytes());
+        }
+    }
+}
+
+
+
+
+
+
+// Log file path of a program, relative ones are taken from its workingdir
+pub fn log_path(workingdir: Option<&str>, path: &str) -> PathBuf {
+    Path::new(workingdir.unwrap_or(".")).join(path)
+}
+
+pub fn open_log(path: &Path) -> io::Result<File> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    File::options().create(true).append(true).open(path)
+}
+
+
+
+
+
+
+/*
+    @@@
+    @forward_to_file();
+    . Copies a child's stdout (or stderr) pipe into its log file until the child closes it, for programs with 'reopen_logs'.
+    . Reopens the file by path after logger::reopen() (SIGUSR2), so a rotated log is let go and a new one created.
+    . Nobody reads the pipe while no supervisor runs, what the child writes then is lost.
+*/
+pub async fn forward_to_file(path: PathBuf, pid: Pid, mut file: File, fd: OwnedFd) {
+    let _registration = Registration::new(fd.as_raw_fd(), pid, PipeTarget::File(path.clone()));
+    let Ok(mut reader) = pipe::Receiver::from_owned_fd(fd) else {
+        return;
+    };
+    let mut generation = logger::generation();
+    let mut buf = vec![0u8; 8192];
+
+    loop {
+        let n = match reader.read(&mut buf).await {
+            Ok(0) | Err(_) => break,
+            Ok(n) => n,
+        };
+        if generation != logger::generation() {
+            generation = logger::generation();
+            match open_log(&path) {
+                Ok(reopened) => file = reopened,
+                Err(e) => tracing::warn!(path = %path.display(), "Cannot reopen log file: {}", e),
+            }
+        }
+        let _ = file.write_all(&buf[..n]);
+    }
+}
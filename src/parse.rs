@@ -1,14 +1,20 @@
-use serde::Deserialize;
+use crate::ini;
+use crate::validate::{self, validate, ConfigError, Problem};
+use nix::sys::signal::Signal;
+use serde::{Deserialize, Serialize};
+use serde_yaml::{Mapping, Value};
 use std::{collections::HashMap, fs};
+use std::path::Path;
+use std::sync::OnceLock;
 
-#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
 #[serde(untagged)]
 pub enum OneOrMany<T> {
     One(T),
     Many(Vec<T>),
 }
 
-#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
 #[serde(rename_all = "lowercase")]
 pub enum RestartPolicy {
     Always,
@@ -16,17 +22,47 @@ pub enum RestartPolicy {
     Unexpected,
 }
 
+// How `restart` replaces the instances of a program: all at once, or a few at a time
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RestartStrategy {
+    All,
+    Rolling,
+}
+
+// Files whose changes restart a program (dev watch mode)
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct WatchConfig {
+    pub paths: Vec<String>,
+    #[serde(default)]
+    pub ignore: Vec<String>,
+    #[serde(default = "default_watch_debounce")]
+    pub debounce_ms: u64,
+}
+
+fn default_numprocs() -> usize { 1 }
 fn default_exitcodes() -> OneOrMany<u32> { OneOrMany::One(0) }
 fn default_autostart() -> bool { true }
 fn default_autorestart() -> RestartPolicy { RestartPolicy::Never }
+fn default_buffer_size() -> usize { 10 }
+fn default_hook_timeout() -> u64 { 30 }
+fn default_on_failure_lines() -> usize { 20 }
+fn default_cpu_window() -> u64 { 60 }
+fn default_watch_debounce() -> u64 { 300 }
+fn default_restart_strategy() -> RestartStrategy { RestartStrategy::All }
+fn default_restart_batch() -> usize { 1 }
+fn default_control_socket() -> String { "logs/supervisor.sock".to_string() }
+fn default_pidfile() -> String { "logs/supervisor.pid".to_string() }
+fn default_state_file() -> String { "logs/supervisor.state".to_string() }
 
 
-#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
 #[allow(dead_code)]
 pub struct ProgramConfig {
     pub cmd: String,
     pub args: Vec<String>,
-    #[serde(default)]
+    #[serde(default = "default_numprocs")]
     pub numprocs: usize,
     #[serde(default)]
     pub umask: Option<String>,
@@ -47,12 +83,76 @@ pub struct ProgramConfig {
     pub stoptime: usize,
     pub stdout: Option<String>,
     pub stderr: Option<String>,
+    // Output goes through the supervisor, which can then reopen the log files on SIGUSR2
+    #[serde(default)]
+    pub reopen_logs: bool,
     pub env: Option<HashMap<String, String>>,
+    #[serde(default)]
+    pub env_file: Vec<String>,
+    #[serde(default)]
+    pub clear_env: bool,
+    #[serde(default)]
+    pub pass_env: Vec<String>,
+    #[serde(default)]
+    pub secrets: HashMap<String, String>,
+    #[serde(default)]
+    pub events: Option<Vec<String>>,
+    #[serde(default = "default_buffer_size")]
+    pub buffer_size: usize,
+    pub pre_start: Option<String>,
+    pub post_start: Option<String>,
+    pub pre_stop: Option<String>,
+    pub post_stop: Option<String>,
+    #[serde(default = "default_hook_timeout")]
+    pub hook_timeout: u64,
+    #[serde(default)]
+    pub abort_on_pre_start_failure: bool,
+    pub on_failure: Option<String>,
+    #[serde(default = "default_on_failure_lines")]
+    pub on_failure_lines: usize,
+    pub max_rss: Option<String>,
+    pub max_cpu_percent: Option<f64>,
+    #[serde(default = "default_cpu_window")]
+    pub cpu_window: u64,
+    #[serde(default)]
+    pub critical: bool,
+    pub watch: Option<WatchConfig>,
+    #[serde(default)]
+    pub sockets: Vec<String>,
+    #[serde(default = "default_restart_strategy")]
+    pub restart_strategy: RestartStrategy,
+    #[serde(default = "default_restart_batch")]
+    pub restart_batch: usize,
 }
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct Config {
+    #[serde(default)]
     pub programs: HashMap<String, ProgramConfig>,
+    #[serde(default)]
+    pub include: Vec<String>,
+    #[serde(default)]
+    pub defaults: Mapping,
+    #[serde(default)]
+    pub templates: HashMap<String, Mapping>,
+    // File each program was defined in, filled by parser()
+    #[serde(skip)]
+    pub origins: HashMap<String, String>,
+    // supervisord INI options that were skipped, with their file, filled by parser() to be shown as warnings
+    #[serde(skip)]
+    pub notes: Vec<String>,
+    #[serde(default)]
+    pub on_failure: Option<String>,
+    #[serde(default)]
+    pub metrics_addr: Option<String>,
+    #[serde(default = "default_control_socket")]
+    pub control_socket: String,
+    #[serde(default = "default_pidfile")]
+    pub pidfile: String,
+    #[serde(default = "default_state_file")]
+    pub state_file: String,
+    #[serde(default)]
+    pub watch_config: bool,
 }
 
 
@@ -61,10 +161,324 @@ pub struct Config {
     @@@
     @parser();
     . Reads the content of config.yml into a String. Any I/O error (file not found, permission denied, etc.) is returned as an Err.
-    . Hands the raw YAML text to serde_yaml, which parses and to map it into config struct. If the YAML is malformed, an error is returned.
+    . Parses it according to its format: YAML, supervisord INI (.ini/.conf), TOML (.toml) or JSON (.json), or the one given with --format.
+    . Hands the parsed value to validate(), which maps it into config struct. If the YAML is malformed or has invalid values, every problem is returned at once.
+    . Loads the programs of every included file (see load_includes()) and records the file each program comes from.
+    . Keeps the notes on what was skipped in INI files, for the caller to warn about.
+    . Fills in the global on_failure command for programs that don't set their own.
 */
 pub fn parser(path: &str) -> Result<Config, Box<dyn std::error::Error>> {
-    let yaml_file = fs::read_to_string(path)?;
-    let parsed_config: Config = serde_yaml::from_str(&yaml_file)?;
+    let text = fs::read_to_string(path)?;
+    let format = MAIN_FORMAT.get().copied().unwrap_or(Format::of(path));
+    let (root, notes) = to_value(format, path, &text)?;
+    let mut parsed_config: Config = validate(root, &|keys| locate_key(format, &text, keys), None)?;
+    parsed_config.notes = notes.into_iter().map(|note| format!("{}: {}", path, note)).collect();
+
+    parsed_config.origins = parsed_config.programs.keys().map(|name| (name.clone(), path.to_string())).collect();
+    load_includes(path, &mut parsed_config)?;
+
+    for prog in parsed_config.programs.values_mut() {
+        if prog.on_failure.is_none() {
+            prog.on_failure = parsed_config.on_failure.clone();
+        }
+    }
     Ok(parsed_config)
 }
+
+
+
+
+
+
+/*
+    @@@
+    @control_socket();
+    . Reads only the 'control_socket' of a config file, for `supervisor ctl`, falling back to its default.
+    . Nothing else is validated nor expanded: the client may lack the programs' environment variables, commands or directories.
+*/
+pub fn control_socket(path: &str) -> Result<String, Box<dyn std::error::Error>> {
+    let text = fs::read_to_string(path)?;
+    let format = MAIN_FORMAT.get().copied().unwrap_or(Format::of(path));
+    let (root, _) = to_value(format, path, &text)?;
+    match root.get("control_socket") {
+        None | Some(Value::Null) => Ok(default_control_socket()),
+        Some(Value::String(socket)) => Ok(socket.clone()),
+        Some(_) => Err("control_socket: expected a path".into()),
+    }
+}
+
+
+
+
+
+
+// Config file formats, picked from the file extension (or --format for the main file)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Yaml,
+    Ini,
+    Toml,
+    Json,
+}
+
+// Format of the main config file given with --format, instead of its extension
+static MAIN_FORMAT: OnceLock<Format> = OnceLock::new();
+
+impl Format {
+    pub fn of(path: &str) -> Format {
+        match Path::new(path).extension().and_then(|ext| ext.to_str()) {
+            Some("ini") | Some("conf") => Format::Ini,
+            Some("toml") => Format::Toml,
+            Some("json") => Format::Json,
+            _ => Format::Yaml,
+        }
+    }
+
+    pub fn set_main(format: Format) {
+        let _ = MAIN_FORMAT.set(format);
+    }
+}
+
+impl std::str::FromStr for Format {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name.to_lowercase().as_str() {
+            "yaml" | "yml" => Ok(Format::Yaml),
+            "ini" => Ok(Format::Ini),
+            "toml" => Ok(Format::Toml),
+            "json" => Ok(Format::Json),
+            other => Err(format!("unknown config format `{}` (yaml, ini, toml or json)", other)),
+        }
+    }
+}
+
+
+
+
+
+
+/*
+    @@@
+    @to_value();
+    . Parses the text of a config file into a generic value with the layout of the YAML format, whatever its format.
+    . A syntax error is a single problem, the message of each parser already includes its location.
+    . Also returns what an INI file had that was skipped, the other formats never skip anything.
+*/
+pub fn to_value(format: Format, path: &str, text: &str) -> Result<(Value, Vec<String>), ConfigError> {
+    let value = match format {
+        Format::Yaml => serde_yaml::from_str(text).map_err(|e| ConfigError::new(None, e.to_string())),
+        Format::Ini => return ini::to_value(path, text),
+        Format::Toml => toml::from_str(text).map_err(|e| ConfigError::new(None, e.to_string().trim_end().to_string())),
+        Format::Json => serde_json::from_str(text).map_err(|e| ConfigError::new(None, e.to_string())),
+    };
+    value.map(|value| (value, Vec::new()))
+}
+
+// Line and column of a key path (e.g. ["programs", "web", "umask"]) in the text of a config file
+pub fn locate_key(format: Format, text: &str, keys: &[&str]) -> Option<(usize, usize)> {
+    match format {
+        Format::Yaml => validate::locate(text, keys),
+        Format::Ini => ini::locate(text, keys),
+        Format::Toml | Format::Json => validate::locate_in_order(text, keys),
+    }
+}
+
+
+
+
+
+
+/*
+    @@@
+    @load_includes();
+    . Expands each 'include' glob (relative to the directory of the main file) and reads the matching files in sorted order.
+    . Each file is validated on its own and may only define 'programs', which use the main file's defaults and templates.
+    . A pattern matching no file is not an error.
+    . A program name already defined in another file is rejected, the problems of all the files are reported together.
+*/
+fn load_includes(path: &str, cfg: &mut Config) -> Result<(), ConfigError> {
+    let base = Path::new(path).parent().unwrap_or(Path::new(""));
+    let mut problems = Vec::new();
+    let mut problem = |file: &str, location, path: String, message: String| {
+        problems.push(Problem { file: Some(file.to_string()), path, location, message });
+    };
+
+    for pattern in &cfg.include {
+        let pattern = base.join(pattern).to_string_lossy().into_owned();
+        let mut files: Vec<String> = match glob::glob(&pattern) {
+            Ok(paths) => paths.filter_map(Result::ok).map(|p| p.to_string_lossy().into_owned()).collect(),
+            Err(e) => {
+                problem(path, None, "include".to_string(), format!("invalid pattern `{}`: {}", pattern, e));
+                continue;
+            }
+        };
+        files.sort();
+
+        for file in files {
+            let text = match fs::read_to_string(&file) {
+                Ok(text) => text,
+                Err(e) => {
+                    problem(&file, None, String::new(), e.to_string());
+                    continue;
+                }
+            };
+            let format = Format::of(&file);
+            let part = match to_value(format, &file, &text)
+                .and_then(|(root, notes)| {
+                    let part = validate(root, &|keys| locate_key(format, &text, keys), Some(cfg))?;
+                    Ok((part, notes))
+                })
+            {
+                Ok((part, notes)) => {
+                    cfg.notes.extend(notes.into_iter().map(|note| format!("{}: {}", file, note)));
+                    part
+                }
+                Err(ConfigError(file_problems)) => {
+                    for p in file_problems {
+                        problem(&file, p.location, p.path, p.message);
+                    }
+                    continue;
+                }
+            };
+            for (name, prog) in part.programs {
+                if let Some(first) = cfg.origins.get(&name) {
+                    let location = locate_key(format, &text, &["programs", &name]);
+                    problem(&file, location, format!("programs.{}", name), format!("already defined in {}", first));
+                    continue;
+                }
+                cfg.origins.insert(name.clone(), file.clone());
+                cfg.programs.insert(name, prog);
+            }
+        }
+    }
+
+    match problems.is_empty() {
+        true => Ok(()),
+        false => Err(ConfigError(problems)),
+    }
+}
+
+
+
+
+
+
+/*
+    @@@
+    @resolve_program();
+    . Builds the final settings of a program: 'defaults' first, then each template it 'extends' (in order), then its own keys.
+    . Fails on an unknown template or a template extending itself through others.
+*/
+pub fn resolve_program(program: &Mapping, defaults: &Mapping, templates: &HashMap<String, Mapping>) -> Result<Mapping, String> {
+    let mut resolved = defaults.clone();
+    merge(&mut resolved, &extend(program, templates, &mut Vec::new())?);
+    Ok(resolved)
+}
+
+// Settings of a program or template, on top of the templates it extends (which may extend others)
+fn extend(settings: &Mapping, templates: &HashMap<String, Mapping>, chain: &mut Vec<String>) -> Result<Mapping, String> {
+    let parents: Vec<String> = match settings.get("extends") {
+        None => Vec::new(),
+        Some(Value::String(name)) => vec![name.clone()],
+        Some(Value::Sequence(names)) => names
+            .iter()
+            .map(|n| n.as_str().map(String::from).ok_or("expected a template name or a list of names".to_string()))
+            .collect::<Result<_, _>>()?,
+        Some(_) => return Err("expected a template name or a list of names".to_string()),
+    };
+
+    let mut resolved = Mapping::new();
+    for parent in parents {
+        if chain.contains(&parent) {
+            return Err(format!("template cycle: {} -> {}", chain.join(" -> "), parent));
+        }
+        let template = templates.get(&parent).ok_or(format!("unknown template `{}`", parent))?;
+        chain.push(parent);
+        merge(&mut resolved, &extend(template, templates, chain)?);
+        chain.pop();
+    }
+
+    let mut own = settings.clone();
+    own.remove("extends");
+    merge(&mut resolved, &own);
+    Ok(resolved)
+}
+
+// Deep merge: nested mappings (like env) are merged key by key, any other value replaces the previous one
+fn merge(base: &mut Mapping, overlay: &Mapping) {
+    for (key, value) in overlay {
+        match (base.get_mut(key), value) {
+            (Some(Value::Mapping(base_map)), Value::Mapping(overlay_map)) => merge(base_map, overlay_map),
+            _ => {
+                base.insert(key.clone(), value.clone());
+            }
+        }
+    }
+}
+
+
+
+
+
+
+/*
+    @@@
+    @parse_size();
+    . Parses a byte size such as "1048576", "512K", "200MB" or "1G" (powers of 1024).
+    . Returns None if the number or the unit suffix is not recognized.
+*/
+pub fn parse_size(value: &str) -> Option<u64> {
+    let value = value.trim().to_uppercase();
+    let digits = value.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+    let unit = value[digits.len()..].trim_end_matches('B');
+    let multiplier: u64 = match unit {
+        ""  => 1,
+        "K" => 1 << 10,
+        "M" => 1 << 20,
+        "G" => 1 << 30,
+        _   => return None,
+    };
+    digits.trim().parse::<u64>().ok()?.checked_mul(multiplier)
+}
+
+
+
+
+
+
+/*
+    @@@
+    @parse_signal();
+    . Parses a signal name such as "TERM", "sigterm" or "SIGHUP", with or without the SIG prefix.
+    . Returns None for names that aren't a signal.
+*/
+pub fn parse_signal(name: &str) -> Option<Signal> {
+    let name = name.trim().to_uppercase();
+    match name.starts_with("SIG") {
+        true => name.parse().ok(),
+        false => format!("SIG{}", name).parse().ok(),
+    }
+}
+
+
+
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_size_units() {
+        assert_eq!(parse_size("1048576"), Some(1048576));
+        assert_eq!(parse_size("512K"), Some(512 << 10));
+        assert_eq!(parse_size("200mb"), Some(200 << 20));
+        assert_eq!(parse_size(" 1G "), Some(1 << 30));
+        assert_eq!(parse_size("1T"), None);
+        assert_eq!(parse_size("M"), None);
+        assert_eq!(parse_size("1.5G"), None);
+        assert_eq!(parse_size(&format!("{}G", u64::MAX)), None);
+    }
+}
@@ -0,0 +1,181 @@
+use nix::unistd::{sysconf, Pid, SysconfVar};
+use std::collections::HashMap;
+use std::fs;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+
+// Subset of /proc/<pid>/stat used by the supervisor
+#[derive(Debug, Clone, Default)]
+pub struct ProcStat {
+    pub state: char,
+    pub ppid: i32,
+    pub utime: u64,
+    pub stime: u64,
+    pub num_threads: u64,
+    pub starttime: u64,
+    pub rss_pages: u64,
+}
+
+
+
+
+
+
+/*
+    @@@
+    @read_stat();
+    . Reads /proc/<pid>/stat and picks the state, CPU times, thread count, start time and RSS fields.
+    . The command name (field 2) may contain spaces and parentheses, so fields are counted from the last ')'.
+*/
+pub fn read_stat(pid: Pid) -> Option<ProcStat> {
+    let content = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    let rest = &content[content.rfind(')')? + 1..];
+    let fields: Vec<&str> = rest.split_whitespace().collect();
+
+    // fields[0] is field 3 (state) of proc(5)
+    let field = |n: usize| -> Option<u64> { fields.get(n - 3)?.parse().ok() };
+    Some(ProcStat {
+        state: fields.first()?.chars().next()?,
+        ppid: fields.get(1)?.parse().ok()?,
+        utime: field(14)?,
+        stime: field(15)?,
+        num_threads: field(20)?,
+        starttime: field(22)?,
+        rss_pages: field(24)?,
+    })
+}
+
+pub fn clock_ticks() -> u64 {
+    sysconf(SysconfVar::CLK_TCK).ok().flatten().unwrap_or(100) as u64
+}
+
+pub fn page_size() -> u64 {
+    sysconf(SysconfVar::PAGE_SIZE).ok().flatten().unwrap_or(4096) as u64
+}
+
+
+
+
+
+
+/*
+    @@@
+    @boot_time();
+    . Reads the 'btime' line of /proc/stat, the boot time in seconds since the epoch.
+*/
+pub fn boot_time() -> Option<u64> {
+    let content = fs::read_to_string("/proc/stat").ok()?;
+    content
+        .lines()
+        .find_map(|line| line.strip_prefix("btime "))
+        .and_then(|v| v.trim().parse().ok())
+}
+
+
+
+
+
+
+/*
+    @@@
+    @uptime_secs();
+    . Computes how long the process has been running from its start time (in clock ticks after boot).
+*/
+pub fn uptime_secs(stat: &ProcStat) -> Option<f64> {
+    let started = boot_time()? as f64 + stat.starttime as f64 / clock_ticks() as f64;
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).ok()?.as_secs_f64();
+    Some((now - started).max(0.0))
+}
+
+
+
+
+
+
+// Subset of /proc/<pid>/status
+#[derive(Debug, Clone, Default)]
+pub struct ProcStatus {
+    pub vm_rss_kb: u64,
+    pub threads: u64,
+}
+
+pub fn read_status(pid: Pid) -> Option<ProcStatus> {
+    let content = fs::read_to_string(format!("/proc/{}/status", pid)).ok()?;
+    let mut status = ProcStatus::default();
+    for line in content.lines() {
+        let Some((key, value)) = line.split_once(':') else { continue };
+        let value = value.split_whitespace().next().and_then(|v| v.parse().ok()).unwrap_or(0);
+        match key {
+            "VmRSS"   => status.vm_rss_kb = value,
+            "Threads" => status.threads = value,
+            _ => {}
+        }
+    }
+    Some(status)
+}
+
+// Subset of /proc/<pid>/io, only readable by the process owner (or root)
+#[derive(Debug, Clone, Default)]
+pub struct ProcIo {
+    pub read_bytes: u64,
+    pub write_bytes: u64,
+}
+
+pub fn read_io(pid: Pid) -> Option<ProcIo> {
+    let content = fs::read_to_string(format!("/proc/{}/io", pid)).ok()?;
+    let mut io = ProcIo::default();
+    for line in content.lines() {
+        let Some((key, value)) = line.split_once(':') else { continue };
+        let value = value.trim().parse().unwrap_or(0);
+        match key {
+            "read_bytes"  => io.read_bytes = value,
+            "write_bytes" => io.write_bytes = value,
+            _ => {}
+        }
+    }
+    Some(io)
+}
+
+pub fn count_fds(pid: Pid) -> Option<usize> {
+    Some(fs::read_dir(format!("/proc/{}/fd", pid)).ok()?.count())
+}
+
+// What an open fd of the process refers to: a file path, or e.g. "pipe:[1234]"
+pub fn fd_target(pid: Pid, fd: i32) -> Option<String> {
+    Some(fs::read_link(format!("/proc/{}/fd/{}", pid, fd)).ok()?.to_string_lossy().into_owned())
+}
+
+
+
+
+
+
+/*
+    @@@
+    @descendants();
+    . Scans every /proc/<pid>/stat once to build the parent->children relation.
+    . Returns all processes below 'root' (children, grandchildren, ...), excluding root itself.
+*/
+pub fn descendants(root: Pid) -> Vec<Pid> {
+    let mut children: HashMap<i32, Vec<i32>> = HashMap::new();
+    if let Ok(entries) = fs::read_dir("/proc") {
+        for entry in entries.flatten() {
+            let Some(pid) = entry.file_name().to_str().and_then(|n| n.parse::<i32>().ok()) else {
+                continue;
+            };
+            if let Some(stat) = read_stat(Pid::from_raw(pid)) {
+                children.entry(stat.ppid).or_default().push(pid);
+            }
+        }
+    }
+
+    let mut found = Vec::new();
+    let mut stack = vec![root.as_raw()];
+    while let Some(pid) = stack.pop() {
+        for child in children.get(&pid).into_iter().flatten() {
+            found.push(Pid::from_raw(*child));
+            stack.push(*child);
+        }
+    }
+    found
+}
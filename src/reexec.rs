@@ -0,0 +1,265 @@
+use crate::control::Context;
+use crate::events;
+use crate::hooks;
+use crate::output::{self, ForwardedPipe, PipeTarget};
+use crate::statefile;
+use nix::fcntl::{fcntl, FcntlArg, FdFlag};
+use nix::unistd::{execve, Pid};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::ffi::CString;
+use std::os::fd::{FromRawFd, OwnedFd, RawFd};
+use std::os::unix::ffi::OsStrExt;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use tokio::time::{sleep, Duration};
+use tracing::{error, info, warn};
+
+
+// Environment variable carrying the Handoff from the old process to the re-executed one
+const HANDOFF_VAR: &str = "SUPERVISOR_REEXEC";
+
+// Seconds the new binary gets to validate the configuration before the upgrade
+const PREFLIGHT_TIMEOUT: u64 = 30;
+
+// File descriptors that stay open across the exec, and what they are
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Handoff {
+    listeners: BTreeMap<String, RawFd>,
+    pipes: Vec<ForwardedPipe>,
+}
+
+// Listening sockets of the supervisor by name ("control", "metrics")
+static LISTENERS: Mutex<BTreeMap<String, RawFd>> = Mutex::new(BTreeMap::new());
+
+// What the previous process handed over, until each part is taken back
+static INHERITED: Mutex<Option<Handoff>> = Mutex::new(None);
+
+static RESUMED: AtomicBool = AtomicBool::new(false);
+
+// Set by a successful prepare(), the exec happens once the reply went out (see run_pending())
+static PENDING: AtomicBool = AtomicBool::new(false);
+
+
+
+
+
+
+/*
+    @@@
+    @take_handoff();
+    . Reads what a self-upgrade handed over and removes it from the environment, so programs don't inherit it.
+    . Marks every handed over descriptor close-on-exec right away, so none leaks into a program before it's taken back.
+    . Must run at startup, before the runtime threads exist.
+*/
+pub fn take_handoff() {
+    let Some(value) = std::env::var_os(HANDOFF_VAR) else { return };
+    std::env::remove_var(HANDOFF_VAR);
+
+    match serde_json::from_slice::<Handoff>(value.as_bytes()) {
+        Ok(handoff) => {
+            for fd in handoff.listeners.values().copied().chain(handoff.pipes.iter().map(|pipe| pipe.fd)) {
+                set_cloexec(fd, true);
+            }
+            *INHERITED.lock().unwrap_or_else(|e| e.into_inner()) = Some(handoff);
+            RESUMED.store(true, Ordering::Relaxed);
+        }
+        Err(e) => eprintln!("Ignoring malformed {}: {}", HANDOFF_VAR, e),
+    }
+}
+
+// True in a process started by a self-upgrade
+pub fn resumed() -> bool {
+    RESUMED.load(Ordering::Relaxed)
+}
+
+fn set_cloexec(fd: RawFd, cloexec: bool) {
+    let flags = if cloexec { FdFlag::FD_CLOEXEC } else { FdFlag::empty() };
+    let _ = fcntl(fd, FcntlArg::F_SETFD(flags));
+}
+
+// Takes ownership of an inherited descriptor, closed again on the next exec
+fn adopt_fd(fd: RawFd) -> OwnedFd {
+    set_cloexec(fd, true);
+    unsafe { OwnedFd::from_raw_fd(fd) }
+}
+
+
+
+
+
+
+/*
+    @@@
+    @inherited_listener();
+    . Returns the listening socket named 'name' handed over by the previous process, if any, to be used instead of binding a new one.
+*/
+pub fn inherited_listener(name: &str) -> Option<OwnedFd> {
+    let mut inherited = INHERITED.lock().unwrap_or_else(|e| e.into_inner());
+    inherited.as_mut()?.listeners.remove(name).map(adopt_fd)
+}
+
+// Takes back every inherited listening socket whose name starts with 'prefix', with their names
+pub fn inherited_listeners(prefix: &str) -> Vec<(String, OwnedFd)> {
+    let mut inherited = INHERITED.lock().unwrap_or_else(|e| e.into_inner());
+    let Some(handoff) = inherited.as_mut() else { return Vec::new() };
+    let (taken, kept) = std::mem::take(&mut handoff.listeners).into_iter().partition(|(name, _)| name.starts_with(prefix));
+    handoff.listeners = kept;
+    taken.into_iter().map(|(name, fd): (String, RawFd)| (name, adopt_fd(fd))).collect()
+}
+
+// Records a listening socket, so a self-upgrade hands it over instead of closing it
+pub fn register_listener(name: &str, fd: RawFd) {
+    LISTENERS.lock().unwrap_or_else(|e| e.into_inner()).insert(name.to_string(), fd);
+}
+
+
+
+
+
+
+/*
+    @@@
+    @resume_pipes();
+    . Forwards again the program output pipes handed over by the previous process, to their log file or the console.
+*/
+pub fn resume_pipes() {
+    let pipes = INHERITED.lock().unwrap_or_else(|e| e.into_inner())
+        .as_mut()
+        .map(|handoff| std::mem::take(&mut handoff.pipes))
+        .unwrap_or_default();
+
+    for pipe in pipes {
+        let (fd, pid) = (adopt_fd(pipe.fd), Pid::from_raw(pipe.pid));
+        match pipe.target {
+            PipeTarget::File(path) => match output::open_log(&path) {
+                Ok(file) => {
+                    tokio::spawn(output::forward_to_file(path, pid, file, fd));
+                }
+                Err(e) => warn!(path = %path.display(), "Cannot reopen log file: {}", e),
+            },
+            PipeTarget::Console { prefix, stderr } => {
+                tokio::spawn(output::forward_to_console(prefix, pid, fd, stderr));
+            }
+        }
+    }
+}
+
+// Path of the running binary, or of the one that replaced it on disk
+fn executable() -> std::io::Result<PathBuf> {
+    let exe = std::env::current_exe()?;
+    let exe = exe.to_string_lossy();
+    Ok(PathBuf::from(exe.strip_suffix(" (deleted)").unwrap_or(&exe)))
+}
+
+fn quote(arg: &str) -> String {
+    format!("'{}'", arg.replace('\'', r"'\''"))
+}
+
+
+
+
+
+
+/*
+    @@@
+    @prepare();
+    . First half of `self-upgrade`: runs the binary on disk with the same arguments and --check, so a binary that can't start or rejects the configuration is never exec'd.
+    . Refused while event listeners run: their stdin/stdout pipes aren't handed over, they would read EOF and lose their events.
+    . On success, marks the upgrade pending and returns the reply, the exec itself happens in run_pending().
+*/
+pub async fn prepare() -> String {
+    let listeners = events::attached_listeners();
+    if listeners > 0 {
+        return format!("Upgrade aborted: {} event listener(s) running, their pipes can't be handed over; stop them first\n", listeners);
+    }
+    let exe = match executable() {
+        Ok(exe) => exe,
+        Err(e) => return format!("Cannot find the supervisor binary: {}\n", e),
+    };
+    let command = std::iter::once(exe.to_string_lossy().to_string())
+        .chain(std::env::args().skip(1))
+        .chain(["--check".to_string()])
+        .map(|arg| quote(&arg))
+        .collect::<Vec<_>>()
+        .join(" ");
+
+    let check = command.clone();
+    let code = tokio::task::spawn_blocking(move || hooks::run_command(&check, &[], None, None, None, PREFLIGHT_TIMEOUT))
+        .await
+        .ok()
+        .flatten();
+    match code {
+        Some(0) => {
+            PENDING.store(true, Ordering::Relaxed);
+            format!("Re-executing {}\n", exe.display())
+        }
+        Some(code) => format!("Upgrade aborted: `{}` failed with exit code {}\n", command, code),
+        None => format!("Upgrade aborted: `{}` could not run or timed out\n", command),
+    }
+}
+
+
+
+
+
+
+/*
+    @@@
+    @run_pending();
+    . Second half of `self-upgrade`, called once the reply of the command was sent: re-executes the binary in place, like systemd's daemon-reexec.
+    . The pid stays the same, so every program instance remains our child and keeps running.
+    . Holds the state lock from the state file write to the exec, so no instance is spawned or reaped in between.
+    . Listening sockets and output pipes stay open across the exec (FD_CLOEXEC cleared) and are described in SUPERVISOR_REEXEC.
+    . The new process adopts the instances of the state file and takes the descriptors back, see take_handoff().
+    . If the exec fails, the descriptors are closed on exec again and the supervisor goes on as before.
+*/
+pub async fn run_pending(ctx: &Context) {
+    if !PENDING.swap(false, Ordering::Relaxed) {
+        return;
+    }
+    let state_file = ctx.config.read().await.state_file.clone();
+    let map = ctx.state.write().await;
+    // A listener may have been started since prepare()
+    if events::attached_listeners() > 0 {
+        warn!("Self-upgrade aborted: an event listener was started meanwhile");
+        return;
+    }
+    statefile::save(&map, &state_file);
+
+    let handoff = Handoff {
+        listeners: LISTENERS.lock().unwrap_or_else(|e| e.into_inner()).clone(),
+        pipes: output::forwarded(),
+    };
+    let fds: Vec<RawFd> = handoff.listeners.values().copied().chain(handoff.pipes.iter().map(|pipe| pipe.fd)).collect();
+    let Ok(handoff) = serde_json::to_string(&handoff) else { return };
+
+    let exe = match executable() {
+        Ok(exe) => exe,
+        Err(e) => {
+            error!("Self-upgrade failed: cannot find the supervisor binary: {}", e);
+            return;
+        }
+    };
+    let to_cstring = |s: &[u8]| CString::new(s).ok();
+    let Some(path) = to_cstring(exe.as_os_str().as_bytes()) else { return };
+    let args: Vec<CString> = std::env::args_os().filter_map(|arg| to_cstring(arg.as_bytes())).collect();
+    let env: Vec<CString> = std::env::vars_os()
+        .filter_map(|(key, value)| to_cstring(&[key.as_bytes(), b"=", value.as_bytes()].concat()))
+        .chain(to_cstring(format!("{}={}", HANDOFF_VAR, handoff).as_bytes()))
+        .collect();
+
+    for fd in &fds {
+        set_cloexec(*fd, false);
+    }
+    info!(exe = %exe.display(), instances = map.values().map(|job| job.children.len()).sum::<usize>(), "Re-executing supervisor");
+    // The log is written by a background thread, give it time to write the line above before exec drops it
+    sleep(Duration::from_millis(100)).await;
+
+    let Err(e) = execve(&path, &args, &env);
+    for fd in &fds {
+        set_cloexec(*fd, true);
+    }
+    error!("Self-upgrade failed: exec {}: {}", exe.display(), e);
+}
@@ -1,32 +1,67 @@
 use crate::parse::{Config, ProgramConfig, OneOrMany, RestartPolicy};
+use crate::events::{self, ProcessState};
+use crate::hooks::{self, Failure, HookKind};
+use crate::metrics;
+use crate::init;
+use crate::output;
+use crate::environ;
+use crate::activation;
+use crate::expand::instance_config;
+use std::sync::atomic::Ordering;
 use tokio::sync::{RwLock};
-use std::collections::HashMap;
-use std::sync::Arc;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
 use tracing::{info, warn};
-use std::fs::File;
 use tokio::time::{sleep, Duration};
 use nix::libc;
-use std::os::unix::io::AsRawFd;
+use std::os::unix::io::{AsRawFd, OwnedFd, RawFd};
 use libc::{STDIN_FILENO, STDOUT_FILENO, STDERR_FILENO};
-use std::fs::OpenOptions;
-use nix::unistd::{fork, ForkResult, execvp, setsid, dup2, Pid};
+use std::fs::{File, OpenOptions};
+use nix::unistd::{fork, ForkResult, execvpe, setsid, dup2, Pid};
 use nix::sys::stat::{umask, Mode};
 use std::ffi::CString;
 use std::path::Path;
 use nix::sys::wait::WaitStatus;
 use nix::sys::wait::WaitPidFlag;
 use nix::sys::wait::waitpid;
+use nix::unistd::pipe2;
+use nix::fcntl::{fcntl, FcntlArg, OFlag};
+use nix::sys::signal::Signal;
 
 
 // Shared map of Runtime data
 // Updated each time the config data changes
 pub type SupervisorState = Arc<RwLock<HashMap<String, RuntimeJob>>>;
 
+// Running instances of a program, by instance number (0 to numprocs - 1)
+pub type Instances = BTreeMap<usize, Pid>;
+
 // Struct for the Parsed Config content with spawned children process
 pub struct RuntimeJob {
     pub config: ProgramConfig,
-    pub children: Vec<Pid>,
+    pub children: Instances,
     pub retries_left: usize,
+    pub restarts: usize,
+    pub last_exit: Option<u32>,
+    // Stopped with `stop`, as opposed to exited on its own
+    pub stopped: bool,
+    // Why an instance went FATAL (out of retries), shown by status until the next start
+    pub fatal: Option<String>,
+}
+
+// An instance that couldn't be spawned, queued for the reaper (see handle_spawn_failure())
+struct SpawnFailure {
+    program: String,
+    instance: usize,
+    reason: String,
+}
+
+static SPAWN_FAILURES: Mutex<Vec<SpawnFailure>> = Mutex::new(Vec::new());
+
+fn spawn_failed(name: &str, instance: usize, reason: String) {
+    warn!(program = name, instance, "{}", reason);
+    SPAWN_FAILURES.lock().unwrap_or_else(|e| e.into_inner())
+        .push(SpawnFailure { program: name.to_string(), instance, reason });
 }
 
 
@@ -39,17 +74,29 @@ pub struct RuntimeJob {
     @apply_config();
     . Updates existing state
     . Stops old processes, starts new ones, and waits the grace period before marking as healthy.
-    . Spawns extra processes if numprocs increased and marks them healthy after the grace period.
+    . Spawns the missing instance numbers if numprocs increased and marks them healthy after the grace period.
     . Starts new programs if autostart is true and marks them healthy --after grace, if set.
     . Logs health status based on whether processes survived the grace period.
+    . The pre_start hook of a program about to spawn runs before the lock is taken, a program whose hook aborts the start is left as it was.
 */
 pub async fn apply_config(
     cfg: &Config,
     state: SupervisorState,
 ) {
-    let mut map = state.write().await;
-
     for (name, prog_cfg) in &cfg.programs {
+        // The pre_start hook may run for a while, it's done before taking the lock
+        let first = match state.read().await.get(name) {
+            Some(job) if job.config == *prog_cfg => (0..prog_cfg.numprocs).find(|num| !job.children.contains_key(num)),
+            Some(_) => Some(0),
+            None => prog_cfg.autostart.then_some(0),
+        };
+        if let Some(num) = first {
+            if !pre_start(name, prog_cfg, num).await {
+                continue;
+            }
+        }
+        let mut map = state.write().await;
+
         match map.get_mut(name) {
             Some(job) => {
                 // … config‐changed branch …
@@ -57,76 +104,23 @@ pub async fn apply_config(
                     // stop old children…
                     let new_pids = spawn_processes(name, prog_cfg);
 
-                    if prog_cfg.starttime > 0 {
-                        let prog = name.clone();
-                        let pids = new_pids.clone();
-                        let grace = prog_cfg.starttime;
-                        tokio::spawn(async move {
-                            sleep(Duration::from_secs(grace)).await;
-
-                            let still_alive = pids.iter().any(|pid| {
-                                matches!(
-                                    waitpid(*pid, Some(WaitPidFlag::WNOHANG)),
-                                    Ok(WaitStatus::StillAlive)
-                                )
-                            });
-
-                            if still_alive {
-                                tracing::info!(
-                                    program = %prog,
-                                    starttime = grace,
-                                    "Marked healthy after grace period"
-                                );
-                            } else {
-                                tracing::warn!(
-                                    program = %prog,
-                                    starttime = grace,
-                                    "Exited before grace period"
-                                );
-                            }
-                        });
-                    } else {
-                        tracing::info!(program = name, starttime = 0, "Marked healthy immediately");
-                    }
+                    watch_grace_period(name, &new_pids, prog_cfg.starttime);
 
                     job.children = new_pids;
                     job.config = prog_cfg.clone();
                     job.retries_left = prog_cfg.startretries;
+                    job.stopped = false;
+                    job.fatal = None;
                 }
 
                 // … scale‐up branch …
                 else if prog_cfg.numprocs > job.children.len() {
-                    let mut extra = spawn_processes(name, prog_cfg);
-
-                    if prog_cfg.starttime > 0 {
-                        let prog = name.clone();
-                        let pids = extra.clone();
-                        let grace = prog_cfg.starttime;
-                        tokio::spawn(async move {
-                            sleep(Duration::from_secs(grace)).await;
-                            let still_alive = pids.iter().any(|pid| {
-                                matches!(
-                                    waitpid(*pid, Some(WaitPidFlag::WNOHANG)),
-                                    Ok(WaitStatus::StillAlive)
-                                )
-                            });
-                            if still_alive {
-                                tracing::info!(
-                                    program = %prog,
-                                    starttime = grace,
-                                    "Marked healthy after grace period"
-                                );
-                            } else {
-                                tracing::warn!(
-                                    program = %prog,
-                                    starttime = grace,
-                                    "Exited before grace period"
-                                );
-                            }
-                        });
-                    } else {
-                        tracing::info!(program = name, starttime = 0, "Marked healthy immediately");
-                    }
+                    let missing: Vec<usize> = (0..prog_cfg.numprocs)
+                        .filter(|num| !job.children.contains_key(num))
+                        .collect();
+                    let mut extra = spawn_instances(name, prog_cfg, missing);
+
+                    watch_grace_period(name, &extra, prog_cfg.starttime);
 
                     job.children.append(&mut extra);
                 }
@@ -137,35 +131,7 @@ pub async fn apply_config(
                 if prog_cfg.autostart {
                     let pids = spawn_processes(name, prog_cfg);
 
-                    if prog_cfg.starttime > 0 {
-                        let prog = name.clone();
-                        let pids = pids.clone();
-                        let grace = prog_cfg.starttime;
-                        tokio::spawn(async move {
-                            sleep(Duration::from_secs(grace)).await;
-                            let still_alive = pids.iter().any(|pid| {
-                                matches!(
-                                    waitpid(*pid, Some(WaitPidFlag::WNOHANG)),
-                                    Ok(WaitStatus::StillAlive)
-                                )
-                            });
-                            if still_alive {
-                                tracing::info!(
-                                    program = %prog,
-                                    starttime = grace,
-                                    "Marked healthy after grace period"
-                                );
-                            } else {
-                                tracing::warn!(
-                                    program = %prog,
-                                    starttime = grace,
-                                    "Exited before grace period"
-                                );
-                            }
-                        });
-                    } else {
-                        tracing::info!(program = name, starttime = 0, "Marked healthy immediately");
-                    }
+                    watch_grace_period(name, &pids, prog_cfg.starttime);
 
                     map.insert(
                         name.clone(),
@@ -173,6 +139,10 @@ pub async fn apply_config(
                             config: prog_cfg.clone(),
                             children: pids,
                             retries_left: prog_cfg.startretries,
+                            restarts: 0,
+                            last_exit: None,
+                            stopped: false,
+                            fatal: None,
                         },
                     );
                 }
@@ -186,21 +156,219 @@ pub async fn apply_config(
 
 
 
+/*
+    @@@
+    @pre_start();
+    . Runs the program's pre_start hook on the blocking pool, callers await it before taking the state lock and spawning.
+    . Returns false when the start must be given up: the hook failed and 'abort_on_pre_start_failure' is set.
+    . The hook gets the config of instance 'num' (the first one being started), placeholders replaced.
+*/
+pub async fn pre_start(name: &str, cfg: &ProgramConfig, num: usize) -> bool {
+    if cfg.pre_start.is_none() {
+        return true;
+    }
+    let (prog, hook_cfg) = (name.to_string(), instance_config(name, cfg, num));
+    let ok = tokio::task::spawn_blocking(move || hooks::run_hook(HookKind::PreStart, &prog, &hook_cfg, None, None))
+        .await
+        .unwrap_or(false);
+    if !ok && cfg.abort_on_pre_start_failure {
+        warn!(program = name, "pre_start hook failed; start aborted");
+        return false;
+    }
+    true
+}
+
+
+
+
+
+
+/*
+    @@@
+    @watch_grace_period();
+    . Marks freshly spawned instances RUNNING once they survived 'starttime' seconds, or immediately when it is 0.
+    . Logs a warning for instances that exited before the grace period elapsed.
+    . Does nothing when no instance was spawned.
+*/
+pub fn watch_grace_period(name: &str, pids: &Instances, grace: u64) {
+    if pids.is_empty() {
+        return;
+    }
+    if grace == 0 {
+        tracing::info!(program = name, starttime = 0, "Marked healthy immediately");
+        for pid in pids.values() {
+            events::process_state(name, *pid, ProcessState::Starting, ProcessState::Running, "");
+        }
+        return;
+    }
+
+    let prog = name.to_string();
+    let pids: Vec<Pid> = pids.values().copied().collect();
+    tokio::spawn(async move {
+        sleep(Duration::from_secs(grace)).await;
+
+        let alive: Vec<Pid> = pids
+            .iter()
+            .copied()
+            .filter(|pid| {
+                matches!(
+                    waitpid(*pid, Some(WaitPidFlag::WNOHANG)),
+                    Ok(WaitStatus::StillAlive)
+                )
+            })
+            .collect();
+
+        if !alive.is_empty() {
+            tracing::info!(
+                program = %prog,
+                starttime = grace,
+                "Marked healthy after grace period"
+            );
+            for pid in alive {
+                events::process_state(&prog, pid, ProcessState::Starting, ProcessState::Running, "");
+            }
+        } else {
+            tracing::warn!(
+                program = %prog,
+                starttime = grace,
+                "Exited before grace period"
+            );
+        }
+    });
+}
+
+
+
+
+
+
 /*
     @@@
     @spawn_processes();
-    . Forks as many as 'numprocs' processes and detaches into a new session (setsid()).
-    . Changes working directory, umask, and environment if specified, and redirect stdout/stderr to log files if configured.
-    . Executes the command using execvp().
+    . Spawns every instance of a program, numbered from 0 to numprocs - 1.
+*/
+pub fn spawn_processes(name: &str, cfg: &ProgramConfig) -> Instances {
+    spawn_instances(name, cfg, 0..cfg.numprocs)
+}
+
+
+
+
+
+
+/*
+    @@@
+    @spawn_instances();
+    . Forks one process per given instance number and detaches into a new session (setsid()).
+    . Each instance gets its own config, with {program}, {instance} and {instance+N} replaced.
+    . Builds its environment before forking (see environ::build_env()), an unreadable env_file skips the instance.
+    . A skipped instance is queued for handle_spawn_failure(), which retries it within 'startretries' or marks it FATAL.
+    . Changes working directory and umask if specified, and redirects stdout/stderr to the log files if configured.
+    . The log files are opened before forking, and the child writes to them directly so its output outlives the supervisor.
+    . With 'reopen_logs', the child writes to pipes the supervisor copies into the files instead, so SIGUSR2 can reopen them.
+    . Passes the program's listening 'sockets' as fds 3, 4, ... with LISTEN_FDS, LISTEN_PID and LISTEN_FDNAMES (systemd socket activation).
+    . Executes the command with its environment using execvpe(), then runs the post_start hook for each instance in the background.
+    . The pre_start hook isn't run here, see pre_start().
 */
-pub fn spawn_processes(name: &str, cfg: &ProgramConfig) -> Vec<Pid> {
-    let mut pids = Vec::with_capacity(cfg.numprocs);
+pub fn spawn_instances(name: &str, cfg: &ProgramConfig, instances: impl IntoIterator<Item = usize>) -> Instances {
+    let mut pids = Instances::new();
+
+    for num in instances {
+        let cfg = &instance_config(name, cfg, num);
+        let mut env = match environ::build_env(name, cfg, num) {
+            Ok(env) => env,
+            Err(e) => {
+                spawn_failed(name, num, format!("Cannot build environment: {}", e));
+                continue;
+            }
+        };
+        let listen_fds = match activation::program_sockets(name, cfg) {
+            Ok(listen_fds) => listen_fds,
+            Err(e) => {
+                spawn_failed(name, num, format!("Cannot open listening socket: {}", e));
+                continue;
+            }
+        };
+        if !listen_fds.is_empty() {
+            let names: Vec<&str> = listen_fds.iter().map(|(name, _)| name.as_str()).collect();
+            env.push(("LISTEN_FDS".to_string(), listen_fds.len().to_string()));
+            env.push(("LISTEN_FDNAMES".to_string(), names.join(":")));
+        }
+        let mut env_c = environ::to_cstrings(&env);
+
+        // Event listeners talk to the supervisor over their stdin/stdout
+        let listener_pipes = match &cfg.events {
+            Some(_) => match (pipe2(OFlag::O_CLOEXEC), pipe2(OFlag::O_CLOEXEC)) {
+                (Ok(stdin_pipe), Ok(stdout_pipe)) => Some((stdin_pipe, stdout_pipe)),
+                (Err(e), _) | (_, Err(e)) => {
+                    spawn_failed(name, num, format!("Failed to create event listener pipes: {}", e));
+                    continue;
+                }
+            },
+            None => None,
+        };
+
+        // In init mode, output without a log file is printed by the supervisor with a '[program:pid]' prefix
+        let console_pipe = |path: &Option<String>| match init::enabled() && path.is_none() {
+            true => pipe2(OFlag::O_CLOEXEC).ok(),
+            false => None,
+        };
+        let stdout_pipe = if listener_pipes.is_none() { console_pipe(&cfg.stdout) } else { None };
+        let stderr_pipe = console_pipe(&cfg.stderr);
+
+        // Opened in append mode, so a copytruncate rotation doesn't leave a hole, and fed through a pipe with 'reopen_logs'
+        let log_file = |path: Option<&String>| -> std::io::Result<_> {
+            let Some(path) = path else { return Ok(None) };
+            let path = output::log_path(cfg.workingdir.as_deref(), path);
+            let file = output::open_log(&path)?;
+            let pipe = if cfg.reopen_logs { Some(pipe2(OFlag::O_CLOEXEC)?) } else { None };
+            Ok(Some((path, file, pipe)))
+        };
+        let (stdout_log, stderr_log) = match (
+            log_file(cfg.stdout.as_ref().filter(|_| listener_pipes.is_none())),
+            log_file(cfg.stderr.as_ref()),
+        ) {
+            (Ok(stdout_log), Ok(stderr_log)) => (stdout_log, stderr_log),
+            (Err(e), _) | (_, Err(e)) => {
+                spawn_failed(name, num, format!("Cannot open log file: {}", e));
+                continue;
+            }
+        };
 
-    for _ in 0..cfg.numprocs {
         match unsafe { fork() } {
             Ok(ForkResult::Parent { child, .. }) => {
-                info!(program = name, pid = child.as_raw(), "Spawned new instance");
-                pids.push(child);
+                info!(program = name, instance = num, pid = child.as_raw(), "Spawned new instance");
+                metrics::SPAWNS.fetch_add(1, Ordering::Relaxed);
+                events::process_state(name, child, ProcessState::Stopped, ProcessState::Starting, "");
+
+                if let Some(((_, stdin_w), (stdout_r, _))) = listener_pipes {
+                    tokio::spawn(events::serve_listener(
+                        name.to_string(),
+                        child,
+                        stdin_w,
+                        stdout_r,
+                        cfg.events.clone().unwrap_or_default(),
+                        cfg.buffer_size,
+                    ));
+                }
+                for (pipe, is_stderr) in [(stdout_pipe, false), (stderr_pipe, true)] {
+                    if let Some((read_end, _)) = pipe {
+                        let prefix = format!("{}:{}", name, child);
+                        tokio::spawn(output::forward_to_console(prefix, child, read_end, is_stderr));
+                    }
+                }
+                for (path, file, pipe) in [stdout_log, stderr_log].into_iter().flatten() {
+                    if let Some((read_end, _)) = pipe {
+                        tokio::spawn(output::forward_to_file(path, child, file, read_end));
+                    }
+                }
+                if cfg.post_start.is_some() {
+                    let (prog, hook_cfg) = (name.to_string(), cfg.clone());
+                    tokio::task::spawn_blocking(move || {
+                        hooks::run_hook(HookKind::PostStart, &prog, &hook_cfg, Some(child), None)
+                    });
+                }
+                pids.insert(num, child);
             }
             Ok(ForkResult::Child) => {
                 setsid().expect("setsid failed");
@@ -213,19 +381,14 @@ pub fn spawn_processes(name: &str, cfg: &ProgramConfig) -> Vec<Pid> {
                 if let Some(mask_str) = &cfg.umask {
                     let mask_val = u32::from_str_radix(mask_str, 8)
                         .expect("invalid umask");
-                    let mode = Mode::from_bits_truncate(mask_val.try_into().unwrap());
+                    let mode = Mode::from_bits_truncate(mask_val);
                     umask(mode);
                 }
 
-                if let Some(envs) = &cfg.env {
-                    for (k, v) in envs {
-                        std::env::set_var(k, v);
-                    }
-                }
-
-                if let Some(parent) = Path::new(&cfg.stdout.clone().unwrap_or_default()).parent()
-                {
-                    std::fs::create_dir_all(parent).ok();
+                // execvpe() looks 'cmd' up in the supervisor's own PATH, make it the child's one
+                match env.iter().find(|(k, _)| k == "PATH") {
+                    Some((_, path)) => std::env::set_var("PATH", path),
+                    None => std::env::remove_var("PATH"),
                 }
 
                 let devnull = OpenOptions::new()
@@ -234,35 +397,40 @@ pub fn spawn_processes(name: &str, cfg: &ProgramConfig) -> Vec<Pid> {
                     .open("/dev/null")
                     .expect("failed to open /dev/null");
                 let null_fd = devnull.as_raw_fd();
-                dup2(null_fd, STDIN_FILENO).ok();
+                match &listener_pipes {
+                    Some(((stdin_r, _), _)) => dup2(stdin_r.as_raw_fd(), STDIN_FILENO).ok(),
+                    None => dup2(null_fd, STDIN_FILENO).ok(),
+                };
 
-                let stdout_file: File = if let Some(ref path) = cfg.stdout {
-                    if let Some(dir) = Path::new(path).parent() {
-                        std::fs::create_dir_all(dir).ok();
-                    }
-                    File::options()
-                        .create(true)
-                        .append(true)
-                        .open(path)
-                        .expect("failed to open stdout file")
-                } else {
-                    devnull.try_clone().unwrap()
+                // The log file itself, or the write end of its pipe with 'reopen_logs'
+                let log_fd = |log: &Option<(_, File, Option<(OwnedFd, OwnedFd)>)>| log.as_ref().map(|(_, file, pipe)| match pipe {
+                    Some((_, log_w)) => log_w.as_raw_fd(),
+                    None => file.as_raw_fd(),
+                });
+                match (&listener_pipes, &stdout_pipe, log_fd(&stdout_log)) {
+                    (Some((_, (_, stdout_w))), _, _) => dup2(stdout_w.as_raw_fd(), STDOUT_FILENO).ok(),
+                    (None, Some((_, console_w)), _) => dup2(console_w.as_raw_fd(), STDOUT_FILENO).ok(),
+                    (None, None, Some(log_w)) => dup2(log_w, STDOUT_FILENO).ok(),
+                    (None, None, None) => dup2(null_fd, STDOUT_FILENO).ok(),
+                };
+                match (&stderr_pipe, log_fd(&stderr_log)) {
+                    (Some((_, console_w)), _) => dup2(console_w.as_raw_fd(), STDERR_FILENO).ok(),
+                    (None, Some(log_w)) => dup2(log_w, STDERR_FILENO).ok(),
+                    (None, None) => dup2(null_fd, STDERR_FILENO).ok(),
                 };
-                dup2(stdout_file.as_raw_fd(), STDOUT_FILENO).ok();
 
-                let stderr_file: File = if let Some(ref path) = cfg.stderr {
-                    if let Some(dir) = Path::new(path).parent() {
-                        std::fs::create_dir_all(dir).ok();
+                // Socket activation: the listening sockets become fds 3, 4, ... (SD_LISTEN_FDS_START), in order
+                if !listen_fds.is_empty() {
+                    // Copied above the target range first, so moving one socket never overwrites another
+                    let above = 3 + listen_fds.len() as RawFd;
+                    let copies: Vec<RawFd> = listen_fds.iter()
+                        .filter_map(|(_, fd)| fcntl(*fd, FcntlArg::F_DUPFD_CLOEXEC(above)).ok())
+                        .collect();
+                    for (i, fd) in copies.iter().enumerate() {
+                        dup2(*fd, 3 + i as RawFd).ok();
                     }
-                    File::options()
-                        .create(true)
-                        .append(true)
-                        .open(path)
-                        .expect("failed to open stderr file")
-                } else {
-                    devnull.try_clone().unwrap()
-                };
-                dup2(stderr_file.as_raw_fd(), STDERR_FILENO).ok();
+                    env_c.push(CString::new(format!("LISTEN_PID={}", std::process::id())).unwrap());
+                }
 
                 let cmd_c = CString::new(cfg.cmd.clone()).unwrap();
                 let mut args_c = Vec::with_capacity(cfg.args.len() + 1);
@@ -271,8 +439,8 @@ pub fn spawn_processes(name: &str, cfg: &ProgramConfig) -> Vec<Pid> {
                     args_c.push(CString::new(arg.as_str()).unwrap());
                 }
 
-                let Err(e) = execvp(&cmd_c, &args_c);
-                eprintln!("execvp failed: {}", e);
+                let Err(e) = execvpe(&cmd_c, &args_c, &env_c);
+                eprintln!("execvpe failed: {}", e);
                 std::process::exit(1);
             }
             Err(err) => {
@@ -294,18 +462,30 @@ pub fn spawn_processes(name: &str, cfg: &ProgramConfig) -> Vec<Pid> {
     @reap_children();
     . Monitors and handles terminated child processes non-blockingly waiting for any child process to exit.
     . Logs an event if a child exited or calls handle_child_exit(...) to update internal state and possibly restart it.
+    . Any other pid is an orphan reparented to the supervisor (PID 1 or subreaper in init mode), it is just reaped and logged.
+    . Then handles the instances that couldn't be spawned since the last round, so retries are spaced by the polling interval.
 */
 pub async fn reap_children(state: SupervisorState) {
     loop {
         loop {
             match waitpid(Pid::from_raw(-1), Some(WaitPidFlag::WNOHANG)) {
                 Ok(WaitStatus::Exited(pid, code)) => {
+                    if hooks::claim_exit(pid, code) {
+                        continue;
+                    }
                     info!(pid = pid.as_raw(), exit_code = code, "Child process exited");
-                    handle_child_exit(pid, code as u32, &state).await;
+                    if !handle_child_exit(pid, code as u32, None, &state).await {
+                        info!(pid = pid.as_raw(), "Reaped orphan process");
+                    }
                 }
                 Ok(WaitStatus::Signaled(pid, sig, _)) => {
+                    if hooks::claim_exit(pid, 128 + sig as i32) {
+                        continue;
+                    }
                     warn!(pid = pid.as_raw(), signal = ?sig, "Child process killed by signal");
-                    handle_child_exit(pid, 128 + (sig as i32) as u32, &state).await;
+                    if !handle_child_exit(pid, 128 + (sig as i32) as u32, Some(sig), &state).await {
+                        info!(pid = pid.as_raw(), "Reaped orphan process");
+                    }
                 }
                 Ok(WaitStatus::StillAlive) => {
                     break; // no children exited, exit inner loop
@@ -322,6 +502,10 @@ pub async fn reap_children(state: SupervisorState) {
                 }
             }
         }
+        let failures = std::mem::take(&mut *SPAWN_FAILURES.lock().unwrap_or_else(|e| e.into_inner()));
+        for failure in failures {
+            handle_spawn_failure(failure, &state).await;
+        }
         tokio::time::sleep(Duration::from_millis(500)).await;
     }
 }
@@ -339,37 +523,123 @@ pub async fn reap_children(state: SupervisorState) {
     @handle_child_exit();
     . Updates the internal state when a child process exits.
     . Finds exited jobs and removes the PID from the list of active children.
-    . Checks restart policy (Always, Never, or Unexpected) and restart if needed.
-    . Logs whether the process is restarted or not.
+    . Checks restart policy (Always, Never, or Unexpected) and restart if needed, the replacement keeps the same instance number (see respawn_instance()).
+    . Logs whether the process is restarted or not, and runs the on_failure command for crashes that aren't restarted.
+    . In init mode, a dying 'critical' program is never restarted, the whole supervisor exits with its code instead.
+    . Returns false if the pid doesn't belong to any program.
 */
-async fn handle_child_exit(pid: Pid, code_u32: u32, state: &SupervisorState) {
+pub async fn handle_child_exit(pid: Pid, code_u32: u32, signal: Option<Signal>, state: &SupervisorState) -> bool {
     let mut map = state.write().await;
     for (name, job) in map.iter_mut() {
-        if let Some(idx) = job.children.iter().position(|&p| p == pid) {
-            job.children.remove(idx);
+        if let Some(num) = job.children.iter().find(|(_, p)| **p == pid).map(|(num, _)| *num) {
+            job.children.remove(&num);
+            job.last_exit = Some(code_u32);
+            metrics::EXITS.fetch_add(1, Ordering::Relaxed);
+
+            let expected = match &job.config.exitcodes {
+                OneOrMany::One(expected)       => code_u32 == *expected,
+                OneOrMany::Many(expected_list) => expected_list.contains(&code_u32),
+            };
 
             let should_restart = match job.config.autorestart {
                 RestartPolicy::Always => true,
                 RestartPolicy::Never  => false,
-                RestartPolicy::Unexpected => {
-                    match &job.config.exitcodes {
-                        OneOrMany::One(expected)       => code_u32 != *expected,
-                        OneOrMany::Many(expected_list) => !expected_list.contains(&code_u32),
-                    }
-                }
+                RestartPolicy::Unexpected => !expected,
             };
 
+            events::process_state(
+                name, pid, ProcessState::Running, ProcessState::Exited,
+                &format!("expected:{}", expected as u8),
+            );
+
+            if job.config.critical && init::enabled() {
+                tracing::error!(program = name, exit_code = code_u32, "Critical program died; shutting down");
+                hooks::notify_failure(name, &job.config, num, Failure::Exited { pid, exit_code: code_u32, signal }, true);
+                init::request_exit(code_u32 as i32);
+                return true;
+            }
+
             if should_restart && job.retries_left > 0 {
                 job.retries_left -= 1;
-                let new_pids = spawn_processes(name, &job.config);
-                info!(program = name, "Restarting child; {} retries left", job.retries_left);
-                job.children.extend(new_pids);
+                job.restarts += 1;
+                metrics::RESTARTS.fetch_add(1, Ordering::Relaxed);
+                events::process_state(name, pid, ProcessState::Exited, ProcessState::Backoff, "");
+                info!(program = name, instance = num, "Restarting child; {} retries left", job.retries_left);
+                tokio::spawn(respawn_instance(state.clone(), name.clone(), num, job.config.clone()));
             } else {
+                if should_restart {
+                    events::process_state(name, pid, ProcessState::Exited, ProcessState::Fatal, "");
+                    job.fatal = Some(format!("instance {} exited with code {}", num, code_u32));
+                }
                 info!(program = name, "Not restarting (policy: {:?}, retries left: {})",
                       job.config.autorestart, job.retries_left);
+                if should_restart || !expected {
+                    hooks::notify_failure(name, &job.config, num, Failure::Exited { pid, exit_code: code_u32, signal }, should_restart);
+                }
             }
 
-            break;
+            return true;
         }
     }
-}
\ No newline at end of file
+    false
+}
+
+
+
+
+
+
+/*
+    @@@
+    @respawn_instance();
+    . Spawns the replacement of an instance that exited, in its own task so the reaper never waits for the pre_start hook.
+    . Gives up if the program was stopped, reconfigured or got that instance back meanwhile.
+*/
+async fn respawn_instance(state: SupervisorState, name: String, num: usize, cfg: ProgramConfig) {
+    if !pre_start(&name, &cfg, num).await {
+        return;
+    }
+    let mut map = state.write().await;
+    let Some(job) = map.get_mut(&name) else { return };
+    if job.stopped || job.config != cfg || job.children.contains_key(&num) {
+        return;
+    }
+    let new_pids = spawn_instances(&name, &cfg, [num]);
+    watch_grace_period(&name, &new_pids, cfg.starttime);
+    job.children.extend(new_pids);
+}
+
+
+
+
+
+
+/*
+    @@@
+    @handle_spawn_failure();
+    . An instance that couldn't be spawned (see spawn_instances()) is retried like one that exited, within 'startretries'.
+    . Out of retries, it goes FATAL: the reason is shown by status and the on_failure command is run with it.
+    . Nothing is done if the program was stopped or got that instance back meanwhile.
+*/
+async fn handle_spawn_failure(failure: SpawnFailure, state: &SupervisorState) {
+    let SpawnFailure { program: name, instance: num, reason } = failure;
+    let mut map = state.write().await;
+    let Some(job) = map.get_mut(&name) else { return };
+    if job.stopped || job.children.contains_key(&num) {
+        return;
+    }
+    // There is no process, supervisord reports pid 0 for these transitions
+    let no_pid = Pid::from_raw(0);
+
+    if job.retries_left > 0 {
+        job.retries_left -= 1;
+        events::process_state(&name, no_pid, ProcessState::Starting, ProcessState::Backoff, "");
+        info!(program = name.as_str(), instance = num, "Retrying spawn; {} retries left", job.retries_left);
+        tokio::spawn(respawn_instance(state.clone(), name, num, job.config.clone()));
+    } else {
+        events::process_state(&name, no_pid, ProcessState::Backoff, ProcessState::Fatal, "");
+        tracing::error!(program = name.as_str(), instance = num, "Instance could not be spawned; giving up");
+        job.fatal = Some(format!("instance {}: {}", num, reason));
+        hooks::notify_failure(&name, &job.config, num, Failure::SpawnFailed(&reason), true);
+    }
+}
@@ -0,0 +1,86 @@
+use std::borrow::Cow;
+use std::io::{self, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::sync::RwLock;
+
+
+// Every secret value read so far, replaced by REDACTED in whatever the supervisor prints or logs
+static SECRETS: RwLock<Vec<String>> = RwLock::new(Vec::new());
+
+const REDACTED: &str = "[REDACTED]";
+
+
+
+
+
+
+/*
+    @@@
+    @read_secret();
+    . Reads a secret file, refusing it when its permissions let any user read it (mode & 0o004).
+    . Strips the trailing newline and registers the value for redaction before returning it.
+*/
+pub fn read_secret(path: &str) -> io::Result<String> {
+    let mode = std::fs::metadata(path)?.permissions().mode();
+    if mode & 0o004 != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("{} is world-readable (mode {:o}), run `chmod o-r` on it", path, mode & 0o777),
+        ));
+    }
+
+    let value = std::fs::read_to_string(path)?;
+    let value = value.strip_suffix('\n').unwrap_or(&value);
+    let value = value.strip_suffix('\r').unwrap_or(value).to_string();
+    register(&value);
+    Ok(value)
+}
+
+pub fn register(value: &str) {
+    if value.is_empty() {
+        return;
+    }
+    let mut secrets = SECRETS.write().unwrap_or_else(|e| e.into_inner());
+    if !secrets.iter().any(|s| s == value) {
+        secrets.push(value.to_string());
+        // Longest first, so a secret containing another one is replaced whole
+        secrets.sort_by_key(|s| std::cmp::Reverse(s.len()));
+    }
+}
+
+
+
+
+
+
+/*
+    @@@
+    @redact();
+    . Replaces every registered secret value found in 'text' with [REDACTED].
+    . Borrows 'text' as is when it holds none, which is the common case.
+*/
+pub fn redact(text: &str) -> Cow<'_, str> {
+    let secrets = SECRETS.read().unwrap_or_else(|e| e.into_inner());
+    let mut text = Cow::Borrowed(text);
+    for secret in secrets.iter() {
+        if text.contains(secret.as_str()) {
+            text = Cow::Owned(text.replace(secret.as_str(), REDACTED));
+        }
+    }
+    text
+}
+
+// Writer redacting secrets out of what goes through it, used under the supervisor log
+pub struct Redacting<W: Write>(pub W);
+
+impl<W: Write> Write for Redacting<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let text = String::from_utf8_lossy(buf);
+        self.0.write_all(redact(&text).as_bytes())?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
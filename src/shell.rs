@@ -50,16 +50,18 @@ impl Completer for CmdCompleter {
     @run_shell();
     . Parses the config file and initializes a shared, thread‐safe map guarded by an RwLock.
     . Sets up tracing/logging and applies the initial config (spawning all autostart processes).
-    . Returns an async move based on the closures --status, reload, start, stop and exit-- which performs the requested operation.
+    . Returns an async move based on the closures --status, reload, start, stop, restart and exit-- which performs the requested operation.
 */
-pub async fn run_shell<SFut, RFut, StFut, SpFut, OnStatus, OnReload, OnStart, OnStop>(
+pub async fn run_shell<SFut, RFut, StFut, SpFut, RsFut, StatsFut, OnStatus, OnReload, OnStart, OnStop, OnRestart, OnStats>(
     mut on_status: OnStatus,
     mut on_reload: OnReload,
     mut on_start: OnStart,
     mut on_stop: OnStop,
+    mut on_restart: OnRestart,
+    mut on_stats: OnStats,
 ) -> rustyline::Result<()>
 where
-    OnStatus: FnMut() -> SFut + 'static,
+    OnStatus: FnMut(&str) -> SFut + 'static,
     SFut: Future<Output = ()> + 'static,
     OnReload: FnMut() -> RFut + 'static,
     RFut: Future<Output = ()> + 'static,
@@ -67,11 +69,15 @@ where
     StFut: Future<Output = ()> + 'static,
     OnStop: FnMut(&str) -> SpFut + 'static,
     SpFut: Future<Output = ()> + 'static,
+    OnRestart: FnMut(&str) -> RsFut + 'static,
+    RsFut: Future<Output = ()> + 'static,
+    OnStats: FnMut(&str) -> StatsFut + 'static,
+    StatsFut: Future<Output = ()> + 'static,
 {
     let config = Config::builder().build();
     let mut rl = Editor::with_config(config)?;
     rl.set_helper(Some(CmdCompleter {
-        commands: vec!["status", "reload", "start", "stop", "exit", "help", "tail"].into_iter().map(String::from).collect(),
+        commands: vec!["status", "reload", "start", "stop", "restart", "stats", "exit", "help", "tail"].into_iter().map(String::from).collect(),
     }));
     let _ = rl.load_history("logs/history.txt");
 
@@ -82,7 +88,10 @@ where
                 let input = line.trim();
                 rl.add_history_entry(input)?;
                 match input {
-                    "status" => on_status().await,
+                    cmd if cmd == "status" || cmd.starts_with("status ") => {
+                        let args = cmd["status".len()..].trim();
+                        on_status(args).await;
+                    }
                     "reload" => on_reload().await,
                     cmd if cmd.starts_with("start ") => {
                         let name = cmd["start ".len()..].trim();
@@ -92,6 +101,14 @@ where
                         let name = cmd["stop ".len()..].trim();
                         on_stop(name).await;
                     }
+                    cmd if cmd.starts_with("restart ") => {
+                        let args = cmd["restart ".len()..].trim();
+                        on_restart(args).await;
+                    }
+                    cmd if cmd == "stats" || cmd.starts_with("stats ") => {
+                        let args = cmd["stats".len()..].trim();
+                        on_stats(args).await;
+                    }
                     "exit" => {
                         tracing::info!("Supervisor exited!");
                         break
@@ -111,7 +128,7 @@ where
                         let reader = BufReader::new(file);
                         let lines: Vec<String> = reader
                             .lines()
-                            .filter_map(Result::ok)
+                            .map_while(Result::ok)
                             .collect();
 
                         let start = lines.len().saturating_sub(10);
@@ -119,7 +136,7 @@ where
                             println!("{}", line);
                         }
                     }
-                    "help" => println!("start -instance_name --start a program\nstop -instance_name --stop a program\nreload --reload all programs\nstatus --status of all programs\nexit --exit supervisor\ntail --last 10 logs traces"),
+                    "help" => println!("start -instance_name --start a program\nstop -instance_name --stop a program\nrestart [--rolling] -instance_name --restart a program, instance by instance with --rolling\nreload --reload all programs\nstatus [--verbose] --status of all programs (and their config file)\nstats [--tree] [instance_name] --cpu, memory, fds and io of instances\nexit --exit supervisor\ntail --last 10 logs traces"),
                     other => println!("Unknown command: {}", other),
                 }
             }
@@ -0,0 +1,88 @@
+use crate::control::{execute, Context};
+use crate::reexec;
+use std::os::fd::AsRawFd;
+use std::io::{Read, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::UnixListener;
+use tracing::{info, warn};
+
+
+
+
+
+
+/*
+    @@@
+    @serve_control();
+    . Binds the control socket (replacing a stale one) and restricts it to the supervisor's user, or takes it back after a self-upgrade.
+    . Each connection sends one command line, gets the command's output back and is closed.
+*/
+pub async fn serve_control(path: String, ctx: Context) {
+    let listener = match reexec::inherited_listener("control") {
+        Some(fd) => {
+            let listener = std::os::unix::net::UnixListener::from(fd);
+            listener.set_nonblocking(true).and_then(|()| UnixListener::from_std(listener))
+        }
+        None => {
+            if let Some(dir) = Path::new(&path).parent() {
+                std::fs::create_dir_all(dir).ok();
+            }
+            let _ = std::fs::remove_file(&path);
+            UnixListener::bind(&path)
+        }
+    };
+    let listener = match listener {
+        Ok(listener) => listener,
+        Err(e) => {
+            warn!("Failed to bind control socket {}: {}", path, e);
+            return;
+        }
+    };
+    let _ = std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600));
+    reexec::register_listener("control", listener.as_raw_fd());
+    info!("Control socket listening on {}", path);
+
+    loop {
+        let Ok((stream, _)) = listener.accept().await else {
+            continue;
+        };
+        let ctx = ctx.clone();
+
+        tokio::spawn(async move {
+            let (reader, mut writer) = stream.into_split();
+            let mut line = String::new();
+            if BufReader::new(reader).read_line(&mut line).await.unwrap_or(0) == 0 {
+                return;
+            }
+            info!(command = line.trim(), "Control command received");
+            let output = execute(&ctx, &line).await;
+            let _ = writer.write_all(output.as_bytes()).await;
+            let _ = writer.shutdown().await;
+            // A `self-upgrade` replaces the process only once its reply is out
+            reexec::run_pending(&ctx).await;
+        });
+    }
+}
+
+
+
+
+
+
+/*
+    @@@
+    @send_command();
+    . Client side of the control socket, used by `supervisor ctl`.
+    . Writes the command line and reads the whole reply until the supervisor closes the connection.
+*/
+pub fn send_command(path: &str, line: &str) -> std::io::Result<String> {
+    let mut stream = UnixStream::connect(path)?;
+    stream.write_all(format!("{}\n", line).as_bytes())?;
+
+    let mut reply = String::new();
+    stream.read_to_string(&mut reply)?;
+    Ok(reply)
+}
@@ -0,0 +1,204 @@
+use crate::parse::Config;
+use crate::procfs;
+use crate::reexec;
+use crate::runtime::{handle_child_exit, RuntimeJob, SupervisorState};
+use nix::unistd::{getpid, Pid};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
+use tokio::time::{interval, Duration};
+use tracing::{info, warn};
+
+
+// How often the state file is brought up to date
+const SAVE_INTERVAL: Duration = Duration::from_secs(1);
+
+// Exit code reported for an adopted instance that disappeared, it isn't our child so its real code is lost
+const UNKNOWN_EXIT: u32 = 255;
+
+// One running instance as recorded in the state file
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct SavedInstance {
+    pub program: String,
+    pub instance: usize,
+    pub pid: i32,
+    // Seconds since the epoch
+    pub started: u64,
+    // Start time of the pid in clock ticks after boot (/proc/<pid>/stat), tells a reused pid apart
+    pub fingerprint: u64,
+}
+
+
+
+
+
+
+/*
+    @@@
+    @snapshot();
+    . Lists every running instance with its start time and /proc fingerprint, sorted by program and instance number.
+    . Instances whose /proc entry is already gone are left out.
+*/
+fn snapshot(map: &HashMap<String, RuntimeJob>) -> Vec<SavedInstance> {
+    let boot = procfs::boot_time().unwrap_or(0);
+    let mut saved: Vec<SavedInstance> = map.iter()
+        .flat_map(|(name, job)| job.children.iter().map(move |(num, pid)| (name, *num, *pid)))
+        .filter_map(|(name, instance, pid)| {
+            let stat = procfs::read_stat(pid)?;
+            Some(SavedInstance {
+                program: name.clone(),
+                instance,
+                pid: pid.as_raw(),
+                started: boot + stat.starttime / procfs::clock_ticks(),
+                fingerprint: stat.starttime,
+            })
+        })
+        .collect();
+    saved.sort_by(|a, b| (&a.program, a.instance).cmp(&(&b.program, b.instance)));
+    saved
+}
+
+// Writes the state file through a temporary file, so a crash never leaves it half written
+fn write(path: &str, saved: &[SavedInstance]) -> std::io::Result<()> {
+    if let Some(dir) = Path::new(path).parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let tmp = format!("{}.tmp", path);
+    std::fs::write(&tmp, serde_json::to_string_pretty(saved)?)?;
+    std::fs::rename(&tmp, path)
+}
+
+pub fn save(map: &HashMap<String, RuntimeJob>, path: &str) {
+    if let Err(e) = write(path, &snapshot(map)) {
+        warn!(path, "Cannot write state file: {}", e);
+    }
+}
+
+pub fn load(path: &str) -> Vec<SavedInstance> {
+    std::fs::read_to_string(path)
+        .ok()
+        .and_then(|text| serde_json::from_str(&text).ok())
+        .unwrap_or_default()
+}
+
+
+
+
+
+
+/*
+    @@@
+    @persist_state();
+    . Keeps the state file in sync with the running instances, rewriting it only when they changed.
+*/
+pub async fn persist_state(state: SupervisorState, path: String) {
+    let mut ticker = interval(SAVE_INTERVAL);
+    let mut last: Option<Vec<SavedInstance>> = None;
+
+    loop {
+        ticker.tick().await;
+        let saved = snapshot(&*state.read().await);
+        if last.as_ref() == Some(&saved) {
+            continue;
+        }
+        match write(&path, &saved) {
+            Ok(()) => last = Some(saved),
+            Err(e) => warn!(path, "Cannot write state file: {}", e),
+        }
+    }
+}
+
+// True when 'saved' still runs: its pid exists, was started at the recorded time and isn't a zombie
+fn still_running(saved: &SavedInstance) -> bool {
+    procfs::read_stat(Pid::from_raw(saved.pid)).is_some_and(|stat| stat.starttime == saved.fingerprint && stat.state != 'Z')
+}
+
+
+
+
+
+
+/*
+    @@@
+    @adopt();
+    . Puts the instances recorded in the state file back into the supervisor state, before the config is applied.
+    . Only instances that still run (same pid and fingerprint) of a program that is still configured, with an instance number below its numprocs, are adopted.
+    . apply_config() then only spawns the missing instances, and watch_adopted() follows the adopted ones.
+    . Warns about adopted instances whose stdout/stderr is a pipe ('reopen_logs', init mode console): its reader was the crashed supervisor, so that output is lost.
+    . Returns the adopted instances.
+*/
+pub async fn adopt(cfg: &Config, state: &SupervisorState) -> Vec<SavedInstance> {
+    let mut map = state.write().await;
+    let mut adopted = Vec::new();
+
+    for saved in load(&cfg.state_file) {
+        let Some(prog_cfg) = cfg.programs.get(&saved.program) else { continue };
+        if saved.instance >= prog_cfg.numprocs || !still_running(&saved) {
+            continue;
+        }
+        let job = map.entry(saved.program.clone()).or_insert_with(|| RuntimeJob {
+            config: prog_cfg.clone(),
+            children: Default::default(),
+            retries_left: prog_cfg.startretries,
+            restarts: 0,
+            last_exit: None,
+            stopped: false,
+            fatal: None,
+        });
+        if job.children.contains_key(&saved.instance) {
+            continue;
+        }
+        job.children.insert(saved.instance, Pid::from_raw(saved.pid));
+        info!(program = %saved.program, instance = saved.instance, pid = saved.pid, "Adopted running instance");
+        // After a self-upgrade the pipes are handed over and read again, see reexec::resume_pipes()
+        if !reexec::resumed() {
+            for (fd, stream) in [(1, "stdout"), (2, "stderr")] {
+                if procfs::fd_target(Pid::from_raw(saved.pid), fd).is_some_and(|target| target.starts_with("pipe:")) {
+                    warn!(
+                        program = %saved.program, instance = saved.instance, pid = saved.pid, stream,
+                        "Adopted instance writes to a pipe nobody reads anymore; its output is lost until it is restarted"
+                    );
+                }
+            }
+        }
+        adopted.push(saved);
+    }
+    adopted
+}
+
+// Instances recorded in the state file that still run, to warn about duplicates when starting without --adopt
+pub fn running(path: &str) -> usize {
+    load(path).iter().filter(|saved| still_running(saved)).count()
+}
+
+
+
+
+
+
+/*
+    @@@
+    @watch_adopted();
+    . Adopted instances were children of the previous supervisor, waitpid() doesn't see them exit.
+    . Polls them instead and hands the ones that disappeared to handle_child_exit(), with exit code 255 since the real one is lost.
+    . Instances that still are our children (after a re-exec) are left to the reaper, which gets their real exit code.
+    . An instance that was stopped or restarted meanwhile is no longer in the state and is just dropped.
+*/
+pub async fn watch_adopted(state: SupervisorState, mut adopted: Vec<SavedInstance>) {
+    let mut ticker = interval(SAVE_INTERVAL);
+    adopted.retain(|saved| procfs::read_stat(Pid::from_raw(saved.pid)).is_some_and(|stat| stat.ppid != getpid().as_raw()));
+
+    while !adopted.is_empty() {
+        ticker.tick().await;
+        let (gone, alive): (Vec<_>, Vec<_>) = adopted.into_iter().partition(|saved| !still_running(saved));
+        adopted = alive;
+        for saved in gone {
+            let pid = Pid::from_raw(saved.pid);
+            if !state.read().await.values().any(|job| job.children.values().any(|p| *p == pid)) {
+                continue;
+            }
+            info!(program = %saved.program, pid = saved.pid, "Adopted instance exited, exit code unknown");
+            handle_child_exit(pid, UNKNOWN_EXIT, None, &state).await;
+        }
+    }
+}
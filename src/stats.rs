@@ -0,0 +1,196 @@
+use crate::procfs;
+use crate::runtime::{RuntimeJob, SupervisorState};
+use nix::unistd::Pid;
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+use std::sync::{Mutex, OnceLock};
+use std::time::Instant;
+use tokio::time::{interval, Duration};
+
+
+// How often CPU usage is sampled, CPU% is the usage between the last two samples
+pub const SAMPLE_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy)]
+struct Sample {
+    ticks: u64,
+    at: Instant,
+    cpu_percent: f64,
+}
+
+static SAMPLES: OnceLock<Mutex<HashMap<i32, Sample>>> = OnceLock::new();
+
+fn samples() -> &'static Mutex<HashMap<i32, Sample>> {
+    SAMPLES.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+// Resource usage of one instance (or of its whole process tree)
+#[derive(Debug, Clone, Default)]
+pub struct InstanceStats {
+    pub cpu_percent: f64,
+    pub rss_bytes: u64,
+    pub threads: u64,
+    pub fds: usize,
+    pub read_bytes: u64,
+    pub write_bytes: u64,
+}
+
+
+
+
+
+
+/*
+    @@@
+    @sample();
+    . Reads the CPU ticks of each pid and derives its CPU% from the previous sample.
+    . Forgets samples of pids that are not in the list anymore.
+*/
+fn sample(pids: &[Pid]) {
+    let ticks_per_sec = procfs::clock_ticks() as f64;
+    let now = Instant::now();
+    let mut samples = samples().lock().unwrap();
+    let mut seen = HashSet::new();
+
+    for pid in pids {
+        let Some(stat) = procfs::read_stat(*pid) else { continue };
+        let ticks = stat.utime + stat.stime;
+        let cpu_percent = match samples.get(&pid.as_raw()) {
+            Some(prev) if ticks >= prev.ticks => {
+                let wall = now.duration_since(prev.at).as_secs_f64();
+                if wall > 0.0 { (ticks - prev.ticks) as f64 / ticks_per_sec / wall * 100.0 } else { prev.cpu_percent }
+            }
+            _ => 0.0,
+        };
+        samples.insert(pid.as_raw(), Sample { ticks, at: now, cpu_percent });
+        seen.insert(pid.as_raw());
+    }
+
+    samples.retain(|pid, _| seen.contains(pid));
+}
+
+
+
+
+
+
+/*
+    @@@
+    @run_sampler();
+    . Samples every supervised instance and its descendants each SAMPLE_INTERVAL, so CPU% is meaningful when stats are asked.
+*/
+pub async fn run_sampler(state: SupervisorState) {
+    let mut ticker = interval(SAMPLE_INTERVAL);
+    loop {
+        ticker.tick().await;
+        let roots: Vec<Pid> = {
+            let map = state.read().await;
+            map.values().flat_map(|job| job.children.values().copied()).collect()
+        };
+
+        let _ = tokio::task::spawn_blocking(move || {
+            let mut pids = roots.clone();
+            for root in &roots {
+                pids.extend(procfs::descendants(*root));
+            }
+            sample(&pids);
+        })
+        .await;
+    }
+}
+
+pub fn cpu_percent(pid: Pid) -> f64 {
+    samples().lock().unwrap().get(&pid.as_raw()).map(|s| s.cpu_percent).unwrap_or(0.0)
+}
+
+
+
+
+
+
+/*
+    @@@
+    @collect();
+    . Gathers stat, status, io and fd data of the pid from /proc, with the last sampled CPU%.
+    . With 'tree', usage of every descendant is added to the instance's own.
+*/
+pub fn collect(pid: Pid, tree: bool) -> Option<InstanceStats> {
+    let mut pids = vec![pid];
+    if tree {
+        pids.extend(procfs::descendants(pid));
+    }
+
+    let mut total = InstanceStats::default();
+    let mut found = false;
+    for pid in pids {
+        let Some(status) = procfs::read_status(pid) else { continue };
+        found = true;
+        total.cpu_percent += cpu_percent(pid);
+        total.rss_bytes += status.vm_rss_kb * 1024;
+        total.threads += status.threads;
+        total.fds += procfs::count_fds(pid).unwrap_or(0);
+        if let Some(io) = procfs::read_io(pid) {
+            total.read_bytes += io.read_bytes;
+            total.write_bytes += io.write_bytes;
+        }
+    }
+    found.then_some(total)
+}
+
+fn human_bytes(bytes: u64) -> String {
+    let units = ["B", "K", "M", "G", "T"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < units.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 { format!("{}B", bytes) } else { format!("{:.1}{}", value, units[unit]) }
+}
+
+
+
+
+
+
+/*
+    @@@
+    @render_stats();
+    . Formats a table of CPU%, RSS, threads, open fds and I/O bytes for every instance of every program --or only 'program'.
+    . Returns the table as a String so both the shell and other front-ends can print it.
+*/
+pub fn render_stats(map: &HashMap<String, RuntimeJob>, program: Option<&str>, tree: bool) -> String {
+    let mut out = String::new();
+    if let Some(name) = program {
+        if !map.contains_key(name) {
+            return format!("No such program: {}\n", name);
+        }
+    }
+
+    let mut names: Vec<&String> = map.keys().filter(|n| program.is_none_or(|p| p == n.as_str())).collect();
+    names.sort();
+
+    let _ = writeln!(
+        out,
+        "{:<24} {:>8} {:>7} {:>9} {:>7} {:>5} {:>9} {:>9}",
+        "PROGRAM", "PID", "CPU%", "RSS", "THREADS", "FDS", "READ", "WRITE"
+    );
+    for name in names {
+        for pid in map[name].children.values() {
+            match collect(*pid, tree) {
+                Some(s) => {
+                    let _ = writeln!(
+                        out,
+                        "{:<24} {:>8} {:>7.1} {:>9} {:>7} {:>5} {:>9} {:>9}",
+                        name, pid, s.cpu_percent, human_bytes(s.rss_bytes), s.threads, s.fds,
+                        human_bytes(s.read_bytes), human_bytes(s.write_bytes)
+                    );
+                }
+                None => {
+                    let _ = writeln!(out, "{:<24} {:>8} {:>7}", name, pid, "gone");
+                }
+            }
+        }
+    }
+    out
+}
@@ -0,0 +1,490 @@
+use crate::expand::{expand_program, instance_config};
+use crate::parse::{resolve_program, parse_signal, parse_size, Config, ProgramConfig};
+use crate::activation::parse_socket;
+use crate::watcher::watch_roots;
+use serde::de::{self, Deserialize, Deserializer, Visitor};
+use serde_yaml::{Mapping, Value};
+use std::fmt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+
+// One problem found in the configuration, located by its key path (e.g. programs.web.numprocs)
+// 'file' is only set for problems in included files
+#[derive(Debug)]
+pub struct Problem {
+    pub file: Option<String>,
+    pub path: String,
+    pub location: Option<(usize, usize)>,
+    pub message: String,
+}
+
+// Every problem found in a configuration file, reported at once
+#[derive(Debug)]
+pub struct ConfigError(pub Vec<Problem>);
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(file) = &self.file {
+            write!(f, "{}: ", file)?;
+        }
+        if let Some((line, column)) = self.location {
+            write!(f, "line {}, column {}: ", line, column)?;
+        }
+        if !self.path.is_empty() {
+            write!(f, "{}: ", self.path)?;
+        }
+        write!(f, "{}", self.message)
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} problem(s) found", self.0.len())?;
+        for problem in &self.0 {
+            write!(f, "\n  {}", problem)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+// Finds the line and column of a key path in the text of the file being validated
+pub type Locate<'a> = dyn Fn(&[&str]) -> Option<(usize, usize)> + 'a;
+
+impl ConfigError {
+    // A single problem that isn't tied to a key, such as a syntax error
+    pub fn new(location: Option<(usize, usize)>, message: String) -> Self {
+        ConfigError(vec![Problem { file: None, path: String::new(), location, message }])
+    }
+}
+
+
+
+
+
+
+/*
+    @@@
+    @struct_fields();
+    . Returns the field names serde expects for a derived struct, by asking it to deserialize from a deserializer that only records them.
+    . Keeps the unknown-key check in sync with Config and ProgramConfig without a hand-maintained list.
+*/
+fn struct_fields<T: for<'de> Deserialize<'de>>() -> &'static [&'static str] {
+    struct Introspect<'a>(&'a mut &'static [&'static str]);
+
+    impl<'de> Deserializer<'de> for Introspect<'_> {
+        type Error = de::value::Error;
+
+        fn deserialize_any<V: Visitor<'de>>(self, _: V) -> Result<V::Value, Self::Error> {
+            Err(de::Error::custom("not a struct"))
+        }
+
+        fn deserialize_struct<V: Visitor<'de>>(
+            self,
+            _: &'static str,
+            fields: &'static [&'static str],
+            _: V,
+        ) -> Result<V::Value, Self::Error> {
+            *self.0 = fields;
+            Err(de::Error::custom("fields recorded"))
+        }
+
+        serde::forward_to_deserialize_any! {
+            bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+            bytes byte_buf option unit unit_struct newtype_struct seq tuple
+            tuple_struct map enum identifier ignored_any
+        }
+    }
+
+    let mut fields: &'static [&'static str] = &[];
+    let _ = T::deserialize(Introspect(&mut fields));
+    fields
+}
+
+
+
+
+
+
+/*
+    @@@
+    @locate();
+    . Finds the line and column (1-based) of a key path such as ["programs", "web", "umask"] in block-style YAML.
+    . Each key is searched among the lines indented deeper than its parent, until the parent's block ends.
+    . Returns None for keys written in flow style ({ ... }) or not present in the text.
+*/
+pub fn locate(text: &str, path: &[&str]) -> Option<(usize, usize)> {
+    let lines: Vec<&str> = text.lines().collect();
+    let (mut start, mut parent_indent): (usize, isize) = (0, -1);
+    let mut found = None;
+
+    for key in path {
+        found = None;
+        for (idx, line) in lines.iter().enumerate().skip(start) {
+            let content = line.trim_start();
+            if content.is_empty() || content.starts_with('#') {
+                continue;
+            }
+            let indent = (line.len() - content.len()) as isize;
+            if indent <= parent_indent {
+                break;
+            }
+            let name = content.split(':').next().unwrap_or("").trim().trim_matches(|c| c == '"' || c == '\'');
+            if content.contains(':') && name == *key {
+                found = Some((idx, indent));
+                break;
+            }
+        }
+        let (idx, indent) = found?;
+        start = idx + 1;
+        parent_indent = indent;
+    }
+    found.map(|(idx, indent)| (idx + 1, indent as usize + 1))
+}
+
+
+
+
+
+
+/*
+    @@@
+    @locate_in_order();
+    . Finds a key path in TOML or JSON text by searching each key, as a whole word, after the previous one.
+    . Matches `[programs.web]` then `umask = ...` as well as `"programs"`, `"web"` then `"umask"`, a best-effort location.
+*/
+pub fn locate_in_order(text: &str, path: &[&str]) -> Option<(usize, usize)> {
+    let is_word = |c: char| c.is_alphanumeric() || c == '_' || c == '-';
+    let mut from = 0;
+    let mut found = None;
+
+    for key in path {
+        let pos = text[from..].match_indices(key).map(|(i, _)| from + i).find(|&i| {
+            let before = text[..i].chars().next_back();
+            let after = text[i + key.len()..].chars().next();
+            !before.is_some_and(is_word) && !after.is_some_and(is_word)
+        })?;
+        from = pos + key.len();
+        found = Some(pos);
+    }
+
+    let pos = found?;
+    let line_start = text[..pos].rfind('\n').map(|i| i + 1).unwrap_or(0);
+    Some((text[..pos].matches('\n').count() + 1, text[line_start..pos].chars().count() + 1))
+}
+
+
+
+
+
+
+/*
+    @@@
+    @find_executable();
+    . Resolves 'cmd' the way execvp() does: as a path (relative to 'workingdir') when it contains a '/', otherwise through PATH.
+    . PATH is taken from the program's 'env' when it overrides it, from the supervisor's environment when it is inherited.
+*/
+fn find_executable(cfg: &ProgramConfig) -> Option<PathBuf> {
+    let is_executable = |path: &Path| {
+        path.metadata().map(|m| m.is_file() && m.permissions().mode() & 0o111 != 0).unwrap_or(false)
+    };
+
+    if cfg.cmd.contains('/') {
+        let path = match &cfg.workingdir {
+            Some(dir) if !cfg.cmd.starts_with('/') => Path::new(dir).join(&cfg.cmd),
+            _ => PathBuf::from(&cfg.cmd),
+        };
+        return is_executable(&path).then_some(path);
+    }
+
+    // Without PATH in its environment, execvpe() falls back to /bin:/usr/bin
+    let inherits_path = (!cfg.clear_env && cfg.pass_env.is_empty()) || cfg.pass_env.iter().any(|k| k == "PATH");
+    let search_path = cfg.env.as_ref()
+        .and_then(|env| env.get("PATH").cloned())
+        .or_else(|| std::env::var("PATH").ok().filter(|_| inherits_path))
+        .unwrap_or("/bin:/usr/bin".to_string());
+    std::env::split_paths(&search_path)
+        .map(|dir| dir.join(&cfg.cmd))
+        .find(|path| is_executable(path))
+}
+
+
+
+
+
+
+/*
+    @@@
+    @check_program();
+    . Semantic checks on a program that deserialized fine, each failing check is one problem.
+    . numprocs must be at least 1, umask an octal mode, stopsignal a known signal and cmd an executable file.
+    . workingdir is checked for every instance, once its {instance} placeholders are replaced.
+    . Existing secret files must not be world-readable, missing ones may still be mounted before the program starts.
+    . Each 'sockets' entry must be a host:port or a Unix socket path, with an optional valid name.
+    . Watched paths must exist and 'ignore' entries must be valid globs.
+*/
+fn check_program(name: &str, cfg: &ProgramConfig) -> Vec<(&'static str, String)> {
+    let mut problems = Vec::new();
+
+    if cfg.numprocs == 0 {
+        problems.push(("numprocs", "must be at least 1".to_string()));
+    }
+    if let Some(mask) = &cfg.umask {
+        if !matches!(u32::from_str_radix(mask, 8), Ok(v) if v <= 0o777) {
+            problems.push(("umask", format!("`{}` is not an octal mode (e.g. \"022\")", mask)));
+        }
+    }
+    if !cfg.stopsignal.is_empty() && parse_signal(&cfg.stopsignal).is_none() {
+        problems.push(("stopsignal", format!("unknown signal `{}`", cfg.stopsignal)));
+    }
+    let missing_dir = (0..cfg.numprocs.max(1))
+        .filter_map(|num| instance_config(name, cfg, num).workingdir)
+        .find(|dir| !Path::new(dir).is_dir());
+    if let Some(dir) = missing_dir {
+        problems.push(("workingdir", format!("`{}` is not a directory", dir)));
+    }
+    if cfg.cmd.is_empty() {
+        problems.push(("cmd", "must not be empty".to_string()));
+    } else if find_executable(&instance_config(name, cfg, 0)).is_none() {
+        problems.push(("cmd", format!("`{}` is not an executable file or not found on PATH", cfg.cmd)));
+    }
+    for (key, path) in &cfg.secrets {
+        let mode = std::fs::metadata(path).map(|m| m.permissions().mode());
+        if mode.is_ok_and(|mode| mode & 0o004 != 0) {
+            problems.push(("secrets", format!("{}: {} is world-readable, run `chmod o-r` on it", key, path)));
+        }
+    }
+    if let Some(size) = &cfg.max_rss {
+        if parse_size(size).is_none() {
+            problems.push(("max_rss", format!("`{}` is not a size (e.g. \"512M\")", size)));
+        }
+    }
+    if let Some(percent) = cfg.max_cpu_percent {
+        if percent <= 0.0 {
+            problems.push(("max_cpu_percent", "must be greater than 0".to_string()));
+        }
+    }
+    if cfg.cpu_window == 0 {
+        problems.push(("cpu_window", "must be at least 1".to_string()));
+    }
+    if cfg.buffer_size == 0 {
+        problems.push(("buffer_size", "must be at least 1".to_string()));
+    }
+    if cfg.restart_batch == 0 {
+        problems.push(("restart_batch", "must be at least 1".to_string()));
+    }
+    if let Some(message) = cfg.sockets.iter().find_map(|entry| parse_socket(entry, name).err()) {
+        problems.push(("sockets", message));
+    }
+    if let Some(watch) = &cfg.watch {
+        if watch.paths.is_empty() {
+            problems.push(("watch", "`paths` must list at least one file or directory".to_string()));
+        }
+        if let Some(path) = watch_roots(cfg).into_iter().find(|path| !path.exists()) {
+            problems.push(("watch", format!("`{}` does not exist", path.display())));
+        }
+        if let Some(pattern) = watch.ignore.iter().find(|p| glob::Pattern::new(p).is_err()) {
+            problems.push(("watch", format!("`{}` is not a glob pattern", pattern)));
+        }
+    }
+    problems
+}
+
+
+
+
+
+
+/*
+    @@@
+    @field_errors();
+    . Finds which key of a program fails to deserialize, by deserializing it alone next to placeholder 'cmd' and 'args'.
+    . Returns each failing key with serde's message (e.g. invalid type: string "two", expected usize).
+*/
+fn field_errors(program: &Mapping) -> Vec<(String, String)> {
+    let mut errors = Vec::new();
+    for (key, value) in program {
+        let Some(key) = key.as_str() else { continue };
+        let mut single = Mapping::new();
+        single.insert("cmd".into(), "".into());
+        single.insert("args".into(), Value::Sequence(Vec::new()));
+        single.insert(key.into(), value.clone());
+        if let Err(e) = serde_yaml::from_value::<ProgramConfig>(Value::Mapping(single)) {
+            errors.push((key.to_string(), e.to_string()));
+        }
+    }
+    errors
+}
+
+
+
+
+
+
+/*
+    @@@
+    @validate();
+    . Works on the file parsed into a generic value (see parse::to_value()), 'locate' finds key paths in its text.
+    . Resolves every program against 'defaults' and the templates it 'extends' --those of the 'main' config for an included file.
+    . Expands ${VAR} references in the program values, so the checks below see the final values.
+    . Rejects unknown keys at the top level and in every program, then deserializes each key of each program on its own so one bad value doesn't hide the others.
+    . Runs the semantic checks on the keys of every program that deserialized, and global ones (metrics_addr).
+    . Only 'programs' is allowed in an 'included' file, the main file may leave 'programs' out when it has an 'include'.
+    . Collects every problem --sorted by position in the file-- instead of stopping at the first one.
+*/
+pub fn validate(
+    mut root: Value,
+    locate: &Locate,
+    main: Option<&Config>,
+) -> Result<Config, ConfigError> {
+    let included = main.is_some();
+    // A 'programs:' key whose entries are all commented out
+    if let Some(programs @ Value::Null) = root.get_mut("programs") {
+        *programs = Value::Mapping(Mapping::new());
+    }
+
+    let (defaults, templates) = match main {
+        Some(main) => (main.defaults.clone(), main.templates.clone()),
+        None => (
+            root.get("defaults").and_then(Value::as_mapping).cloned().unwrap_or_default(),
+            root.get("templates").and_then(Value::as_mapping).into_iter().flatten()
+                .filter_map(|(name, t)| Some((name.as_str()?.to_string(), t.as_mapping()?.clone())))
+                .collect(),
+        ),
+    };
+
+    let mut expand_errors = Vec::new();
+    let mut unresolved = Vec::new();
+    if let Some(Value::Mapping(programs)) = root.get_mut("programs") {
+        for (name, program) in programs.iter_mut() {
+            if let (Some(name), Value::Mapping(program_map)) = (name.as_str(), program) {
+                match resolve_program(program_map, &defaults, &templates) {
+                    Ok(resolved) => *program_map = resolved,
+                    Err(message) => {
+                        unresolved.push((name.to_string(), message));
+                        continue;
+                    }
+                }
+                for (key, message) in expand_program(program_map) {
+                    expand_errors.push((name.to_string(), key, message));
+                }
+            }
+        }
+    }
+
+    let mut problems = Vec::new();
+    let mut problem = |path: &[&str], message: String| {
+        problems.push(Problem { file: None, path: path.join("."), location: locate(path), message });
+    };
+    for (name, key, message) in &expand_errors {
+        problem(&["programs", name, key], message.clone());
+    }
+    for (name, message) in &unresolved {
+        problem(&["programs", name, "extends"], message.clone());
+    }
+
+    let Some(root_map) = root.as_mapping() else {
+        problem(&[], "expected a mapping with a `programs` key".to_string());
+        return Err(ConfigError(problems));
+    };
+
+    // Included files only bring programs
+    let config_fields = match included {
+        true => &["programs"],
+        false => struct_fields::<Config>(),
+    };
+    for key in root_map.keys() {
+        let key = key.as_str().unwrap_or_default();
+        if !config_fields.contains(&key) {
+            problem(&[key], format!("unknown field, expected one of: {}", config_fields.join(", ")));
+        }
+    }
+
+    let program_fields = struct_fields::<ProgramConfig>();
+    match root_map.get("programs") {
+        Some(Value::Mapping(programs)) => {
+            for (name, program) in programs {
+                let name = name.as_str().unwrap_or_default();
+                if unresolved.iter().any(|(failed, _)| failed == name) {
+                    continue;
+                }
+                let Some(program_map) = program.as_mapping() else {
+                    problem(&["programs", name], "expected a mapping of program settings".to_string());
+                    continue;
+                };
+                for key in program_map.keys() {
+                    let key = key.as_str().unwrap_or_default();
+                    if !program_fields.contains(&key) {
+                        problem(&["programs", name, key], "unknown field".to_string());
+                    }
+                }
+
+                let errors = field_errors(program_map);
+                for (key, message) in &errors {
+                    problem(&["programs", name, key], message.clone());
+                }
+                // The semantic checks still run on the keys that deserialized
+                let mut valid_keys = program_map.clone();
+                for (key, _) in &errors {
+                    valid_keys.remove(key.as_str());
+                }
+                match serde_yaml::from_value::<ProgramConfig>(Value::Mapping(valid_keys)) {
+                    Ok(cfg) => {
+                        for (key, message) in check_program(name, &cfg) {
+                            let failed = errors.iter().any(|(failed, _)| failed == key)
+                                || expand_errors.iter().any(|(prog, failed, _)| prog == name && failed == key);
+                            if !failed {
+                                problem(&["programs", name, key], message);
+                            }
+                        }
+                    }
+                    Err(e) => problem(&["programs", name], e.to_string()),
+                }
+            }
+        }
+        Some(_) => problem(&["programs"], "expected a mapping of program names to settings".to_string()),
+        None if included || !root_map.contains_key("include") => problem(&[], "missing field `programs`".to_string()),
+        None => {}
+    }
+
+    if root_map.get("defaults").is_some_and(|d| !d.is_mapping()) {
+        problem(&["defaults"], "expected a mapping of program settings".to_string());
+    }
+    for (name, template) in root_map.get("templates").and_then(Value::as_mapping).into_iter().flatten() {
+        if !template.is_mapping() {
+            problem(&["templates", name.as_str().unwrap_or_default()], "expected a mapping of program settings".to_string());
+        }
+    }
+
+    if let Some(addr) = root_map.get("metrics_addr").and_then(Value::as_str) {
+        if addr.parse::<std::net::SocketAddr>().is_err() {
+            problem(&["metrics_addr"], format!("`{}` is not an address (e.g. \"127.0.0.1:9100\")", addr));
+        }
+    }
+
+    if problems.is_empty() {
+        return serde_yaml::from_value::<Config>(root).map_err(|e| ConfigError::new(None, e.to_string()));
+    }
+    problems.sort_by_key(|p| p.location.unwrap_or((usize::MAX, 0)));
+    Err(ConfigError(problems))
+}
+
+
+
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn locate_nested_yaml_keys() {
+        let text = "programs:\n  api:\n    cmd: ./api\n  web:\n    # umask: 1\n    umask: \"99\"\n";
+        assert_eq!(locate(text, &["programs", "web", "umask"]), Some((6, 5)));
+        assert_eq!(locate(text, &["programs", "api", "umask"]), None);
+        assert_eq!(locate(text, &["programs", "web"]), Some((4, 3)));
+    }
+}
@@ -0,0 +1,131 @@
+use crate::control::stop_and_cleanup_pid;
+use crate::events;
+use crate::metrics;
+use crate::parse::parse_size;
+use crate::runtime::{pre_start, spawn_instances, watch_grace_period, SupervisorState};
+use crate::stats::{self, SAMPLE_INTERVAL};
+use nix::unistd::Pid;
+use std::collections::HashMap;
+use std::sync::atomic::Ordering;
+use std::time::Instant;
+use tokio::time::{interval, Duration};
+use tracing::warn;
+
+
+
+
+
+
+/*
+    @@@
+    @run_watchdog();
+    . Checks every instance of programs with 'max_rss' or 'max_cpu_percent' each SAMPLE_INTERVAL.
+    . RSS above the limit triggers a restart right away, CPU% must stay above its limit for 'cpu_window' seconds.
+*/
+pub async fn run_watchdog(state: SupervisorState) {
+    let mut ticker = interval(SAMPLE_INTERVAL);
+    let mut over_since: HashMap<i32, Instant> = HashMap::new();
+
+    loop {
+        ticker.tick().await;
+        let mut victims: Vec<(String, Pid, String)> = Vec::new();
+        {
+            let map = state.read().await;
+            for (name, job) in map.iter() {
+                let cfg = &job.config;
+                let max_rss = cfg.max_rss.as_deref().and_then(parse_size);
+                if max_rss.is_none() && cfg.max_cpu_percent.is_none() {
+                    continue;
+                }
+
+                for pid in job.children.values() {
+                    let Some(usage) = stats::collect(*pid, false) else { continue };
+
+                    if let Some(limit) = max_rss {
+                        if usage.rss_bytes > limit {
+                            victims.push((
+                                name.clone(),
+                                *pid,
+                                format!("rss {} bytes exceeds max_rss {} bytes", usage.rss_bytes, limit),
+                            ));
+                            continue;
+                        }
+                    }
+
+                    if let Some(limit) = cfg.max_cpu_percent {
+                        if usage.cpu_percent > limit {
+                            let since = *over_since.entry(pid.as_raw()).or_insert_with(Instant::now);
+                            if since.elapsed() >= Duration::from_secs(cfg.cpu_window) {
+                                victims.push((
+                                    name.clone(),
+                                    *pid,
+                                    format!(
+                                        "cpu {:.1}% above max_cpu_percent {:.1}% for {}s",
+                                        usage.cpu_percent, limit, cfg.cpu_window
+                                    ),
+                                ));
+                            }
+                        } else {
+                            over_since.remove(&pid.as_raw());
+                        }
+                    }
+                }
+            }
+
+            over_since.retain(|pid, _| {
+                map.values().any(|job| job.children.values().any(|p| p.as_raw() == *pid))
+            });
+        }
+
+        for (name, pid, reason) in victims {
+            over_since.remove(&pid.as_raw());
+            restart_instance(&state, &name, pid, &reason).await;
+        }
+    }
+}
+
+
+
+
+
+
+/*
+    @@@
+    @restart_instance();
+    . Emits a PROCESS_LIMIT_EXCEEDED event and detaches the pid from its job, so the reaper won't restart it on its own.
+    . Stops it gracefully with the program's stopsignal/stoptime, then spawns a replacement with the same instance number, unless the program was stopped meanwhile.
+*/
+async fn restart_instance(state: &SupervisorState, name: &str, pid: Pid, reason: &str) {
+    warn!(program = name, pid = pid.as_raw(), reason, "Resource limit exceeded; restarting instance");
+    events::emit(
+        "PROCESS_LIMIT_EXCEEDED",
+        format!("processname:{} groupname:{} pid:{} reason:{}", name, name, pid, reason.replace(' ', "_")),
+    );
+
+    let (num, cfg) = {
+        let mut map = state.write().await;
+        let Some(job) = map.get_mut(name) else { return };
+        let Some(num) = job.children.iter().find(|(_, p)| **p == pid).map(|(num, _)| *num) else { return };
+        job.children.remove(&num);
+        (num, job.config.clone())
+    };
+
+    let (stop_name, stop_cfg) = (name.to_string(), cfg.clone());
+    let _ = tokio::task::spawn_blocking(move || stop_and_cleanup_pid(&stop_name, num, pid, &stop_cfg)).await;
+    if !pre_start(name, &cfg, num).await {
+        return;
+    }
+
+    let mut map = state.write().await;
+    if let Some(job) = map.get_mut(name) {
+        // The program may have been stopped or reconfigured meanwhile
+        if job.config != cfg || job.stopped || job.children.contains_key(&num) {
+            return;
+        }
+        let pids = spawn_instances(name, &cfg, [num]);
+        watch_grace_period(name, &pids, cfg.starttime);
+        job.children.extend(pids);
+        job.restarts += 1;
+        metrics::RESTARTS.fetch_add(1, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,251 @@
+use crate::control::{reload_config, restart_program, Context};
+use crate::expand::instance_config;
+use crate::parse::{ProgramConfig, WatchConfig};
+use notify::{Event, EventKind, RecursiveMode, Watcher};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use tokio::sync::mpsc;
+use tokio::time::{interval, sleep_until, timeout, Duration, Instant};
+use tracing::{info, warn};
+
+
+// Quiet time after the last change before the config is reloaded
+const CONFIG_DEBOUNCE: Duration = Duration::from_millis(500);
+
+// How often the program watches are matched against the config in use (after a reload)
+const WATCH_SYNC: Duration = Duration::from_secs(1);
+
+
+
+
+
+
+/*
+    @@@
+    @config_targets();
+    . Returns the directories to watch for the config file and its 'include' globs, with the glob patterns themselves.
+    . Directories are watched rather than files, so editors replacing a file (write + rename) and new included files are seen.
+*/
+fn config_targets(config_path: &str, include: &[String]) -> (HashSet<(PathBuf, RecursiveMode)>, Vec<glob::Pattern>) {
+    let main = std::path::absolute(config_path).unwrap_or_else(|_| PathBuf::from(config_path));
+    let base = main.parent().unwrap_or(Path::new("/")).to_path_buf();
+    let mut dirs = HashSet::from([(base.clone(), RecursiveMode::NonRecursive)]);
+    let mut patterns = vec![glob::Pattern::new(&glob::Pattern::escape(&main.to_string_lossy())).unwrap()];
+
+    for include in include {
+        let full = base.join(include);
+        let Ok(pattern) = glob::Pattern::new(&full.to_string_lossy()) else { continue };
+        patterns.push(pattern);
+
+        // Deepest directory without wildcards, recursive when the wildcards span several levels
+        let components: Vec<_> = full.components().collect();
+        let fixed = components.iter().take_while(|c| !c.as_os_str().to_string_lossy().contains(['*', '?', '['])).count();
+        let dir: PathBuf = components[..fixed.min(components.len() - 1)].iter().collect();
+        let mode = match components.len() - fixed {
+            0 | 1 => RecursiveMode::NonRecursive,
+            _ => RecursiveMode::Recursive,
+        };
+        dirs.insert((dir, mode));
+    }
+    (dirs, patterns)
+}
+
+
+
+
+
+
+/*
+    @@@
+    @watch_config();
+    . Opt-in with 'watch_config': watches the config file and its included files with inotify.
+    . Waits for changes to stop for CONFIG_DEBOUNCE, then reloads through reload_config(), which validates first and keeps the old config on errors.
+    . Watched directories follow the 'include' of the config in use, after each reload.
+*/
+pub async fn watch_config(ctx: Context) {
+    let (tx, mut rx) = mpsc::unbounded_channel::<Event>();
+    let mut watcher = match notify::recommended_watcher(move |res: notify::Result<Event>| {
+        if let Ok(event) = res {
+            let _ = tx.send(event);
+        }
+    }) {
+        Ok(watcher) => watcher,
+        Err(e) => {
+            warn!("Cannot watch the config file: {}", e);
+            return;
+        }
+    };
+    let mut watched: HashSet<(PathBuf, RecursiveMode)> = HashSet::new();
+
+    loop {
+        let include = ctx.config.read().await.include.clone();
+        let (dirs, patterns) = config_targets(&ctx.config_path, &include);
+        for (dir, _) in watched.difference(&dirs) {
+            let _ = watcher.unwatch(dir);
+        }
+        for (dir, mode) in dirs.difference(&watched) {
+            match watcher.watch(dir, *mode) {
+                Ok(()) => info!(dir = %dir.display(), "Watching config directory"),
+                Err(e) => warn!(dir = %dir.display(), "Cannot watch config directory: {}", e),
+            }
+        }
+        watched = dirs;
+
+        loop {
+            let Some(event) = rx.recv().await else { return };
+            let relevant = matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_) | EventKind::Remove(_))
+                && event.paths.iter().any(|p| patterns.iter().any(|pattern| pattern.matches_path(p)));
+            if relevant {
+                break;
+            }
+        }
+        while let Ok(Some(_)) = timeout(CONFIG_DEBOUNCE, rx.recv()).await {}
+
+        info!(config = %ctx.config_path, "Config file changed");
+        reload_config(&ctx).await;
+    }
+}
+
+
+
+
+
+
+// Path of a program file, relative ones are taken from the program's workingdir like the child does
+fn program_path(cfg: &ProgramConfig, path: &str) -> PathBuf {
+    let dir = cfg.workingdir.as_deref().unwrap_or(".");
+    let path = Path::new(dir).join(path);
+    std::path::absolute(&path).unwrap_or(path)
+}
+
+// Files and directories watched for a program with 'watch'
+pub fn watch_roots(cfg: &ProgramConfig) -> Vec<PathBuf> {
+    let cfg = &instance_config("", cfg, 0);
+    cfg.watch.iter().flat_map(|watch| &watch.paths).map(|path| program_path(cfg, path)).collect()
+}
+
+
+
+
+
+
+// What a program watches, built from its config by ProgramWatch::new()
+struct ProgramWatch {
+    watch: WatchConfig,
+    roots: Vec<PathBuf>,
+    ignore: Vec<glob::Pattern>,
+    logs: Vec<PathBuf>,
+}
+
+impl ProgramWatch {
+    fn new(name: &str, cfg: &ProgramConfig, watch: &WatchConfig) -> Self {
+        // The program's own log files change all the time, they would restart it in a loop
+        let logs = (0..cfg.numprocs)
+            .map(|num| instance_config(name, cfg, num))
+            .flat_map(|cfg| [cfg.stdout.clone(), cfg.stderr.clone()].into_iter().flatten().map(move |log| program_path(&cfg, &log)))
+            .collect();
+        ProgramWatch {
+            watch: watch.clone(),
+            roots: watch_roots(cfg),
+            ignore: watch.ignore.iter().filter_map(|p| glob::Pattern::new(p).ok()).collect(),
+            logs,
+        }
+    }
+
+    /*
+        @@@
+        @matches();
+        . True when 'path' is under one of the watched paths, isn't a log file of the program and isn't ignored.
+        . 'ignore' globs are matched against the path relative to the watched directory and against each of its components,
+          so "*.log" skips log files anywhere and "target" skips a whole directory.
+    */
+    fn matches(&self, path: &Path) -> bool {
+        if self.logs.iter().any(|log| log == path) {
+            return false;
+        }
+        self.roots.iter().any(|root| {
+            let Ok(relative) = path.strip_prefix(root) else { return false };
+            !self.ignore.iter().any(|pattern| {
+                pattern.matches_path(relative) || relative.components().any(|c| pattern.matches(&c.as_os_str().to_string_lossy()))
+            })
+        })
+    }
+}
+
+
+
+
+
+
+/*
+    @@@
+    @watch_programs();
+    . Dev watch mode: restarts a program gracefully (stopsignal, stoptime and stop hooks) when a file under its 'watch' paths changes.
+    . Directories are watched recursively with inotify, changes are coalesced until none came for 'debounce_ms'.
+    . A program that exited on its own (e.g. crashed on a syntax error) is started again, one stopped with `stop` is left alone.
+    . Follows the config in use, so a reload adding or changing a 'watch' is picked up within WATCH_SYNC.
+*/
+pub async fn watch_programs(ctx: Context) {
+    let (tx, mut rx) = mpsc::unbounded_channel::<Event>();
+    let mut watcher = match notify::recommended_watcher(move |res: notify::Result<Event>| {
+        if let Ok(event) = res {
+            let _ = tx.send(event);
+        }
+    }) {
+        Ok(watcher) => watcher,
+        Err(e) => {
+            warn!("Cannot watch program files: {}", e);
+            return;
+        }
+    };
+    let mut programs: HashMap<String, ProgramWatch> = HashMap::new();
+    let mut watched: HashSet<PathBuf> = HashSet::new();
+    let mut pending: HashMap<String, Instant> = HashMap::new();
+    let mut sync = interval(WATCH_SYNC);
+
+    loop {
+        let next = pending.values().min().copied();
+        tokio::select! {
+            _ = sync.tick() => {
+                programs = ctx.config.read().await.programs.iter()
+                    .filter_map(|(name, cfg)| Some((name.clone(), ProgramWatch::new(name, cfg, cfg.watch.as_ref()?))))
+                    .collect();
+                let roots: HashSet<PathBuf> = programs.values().flat_map(|p| p.roots.clone()).collect();
+                for root in watched.difference(&roots) {
+                    let _ = watcher.unwatch(root);
+                }
+                for root in roots.difference(&watched) {
+                    match watcher.watch(root, RecursiveMode::Recursive) {
+                        Ok(()) => info!(path = %root.display(), "Watching program files"),
+                        Err(e) => warn!(path = %root.display(), "Cannot watch program files: {}", e),
+                    }
+                }
+                watched = roots;
+                pending.retain(|name, _| programs.contains_key(name));
+            }
+            Some(event) = rx.recv() => {
+                if !matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_) | EventKind::Remove(_)) {
+                    continue;
+                }
+                for (name, program) in &programs {
+                    if event.paths.iter().any(|path| program.matches(path)) {
+                        pending.insert(name.clone(), Instant::now() + Duration::from_millis(program.watch.debounce_ms));
+                    }
+                }
+            }
+            _ = sleep_until(next.unwrap_or_else(Instant::now)), if next.is_some() => {
+                let now = Instant::now();
+                let due: Vec<String> = pending.iter().filter(|(_, at)| **at <= now).map(|(name, _)| name.clone()).collect();
+                for name in due {
+                    pending.remove(&name);
+                    let restart = ctx.state.read().await.get(&name)
+                        .is_some_and(|job| !job.stopped);
+                    if restart {
+                        info!(program = %name, "Watched files changed; restarting");
+                        restart_program(&name, ctx.state.clone(), false).await;
+                    }
+                }
+            }
+        }
+    }
+}